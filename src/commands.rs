@@ -1,5 +1,5 @@
 use crate::parser::Parser;
-use crate::spreadsheet::Spreadsheet;
+use crate::spreadsheet::{cell_name, Spreadsheet};
 use std::io::{self, BufRead, Write};
 use std::time::Instant;
 
@@ -17,6 +17,7 @@ pub fn handle_commands(sheet: &mut Spreadsheet) {
         "unrecognized cmd",
         "Circular dependency",
         "Division_by_zero",
+        "Overflow",
     ];
     let mut which_message: u8 = 0;
     let mut last_instant = Instant::now();
@@ -24,10 +25,13 @@ pub fn handle_commands(sheet: &mut Spreadsheet) {
     sheet.display(viewport_row, viewport_col, 10, 10);
     loop {
         let elapsed = last_instant.elapsed().as_secs_f64();
-        print!(
-            "[{:.1}] ({}) > ",
-            elapsed, status_messages[which_message as usize]
-        );
+        let mut status = status_messages[which_message as usize].to_string();
+        if which_message == 4 {
+            // Show the loop, e.g. "Circular dependency: A1 -> B1 -> A1"
+            let path: Vec<String> = sheet.last_cycle.iter().map(|&c| cell_name(c)).collect();
+            status = format!("{}: {}", status, path.join(" -> "));
+        }
+        print!("[{:.1}] ({}) > ", elapsed, status);
         stdout.flush().unwrap();
         input.clear();
         if stdin.lock().read_line(&mut input).unwrap() == 0 {
@@ -37,15 +41,17 @@ pub fn handle_commands(sheet: &mut Spreadsheet) {
         which_message = 0;
         let input_trimmed = input.trim_end();
 
-        if input_trimmed == "q" {
-            break;
-        }if input_trimmed == "Q" {
+        if input_trimmed == "q" || input_trimmed == "Q" {
             break;
         } else if input_trimmed == "disable_output" {
             output_enabled = false;
             continue;
         } else if input_trimmed == "enable_output" {
             output_enabled = true;
+        } else if input_trimmed == "disable_parallel" {
+            sheet.parallel = false;
+        } else if input_trimmed == "enable_parallel" {
+            sheet.parallel = true;
         } else if input_trimmed.starts_with("scroll_to") {
             let parts: Vec<&str> = input_trimmed.split_whitespace().collect();
             if parts.len() >= 2 {
@@ -84,6 +90,12 @@ pub fn handle_commands(sheet: &mut Spreadsheet) {
             } else {
                 viewport_col = sheet.cols - 10;
             }
+        } else if let Some(seed) = input_trimmed.strip_prefix("seed ") {
+            // Make RAND and RANDBETWEEN reproducible
+            match seed.trim().parse() {
+                Ok(seed) => sheet.set_seed(seed),
+                Err(_) => which_message = 3,
+            }
         } else if let Some(pos) = input_trimmed.find('=') {
             let (cell_str, expr) = input_trimmed.split_at(pos);
             let expr = &expr[1..]; // skip '='
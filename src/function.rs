@@ -1,180 +1,1636 @@
+use crate::parser::{BinOp, Expr, RangeRef};
+use crate::spreadsheet::{date_serial, format_date, serial_date, Cell, CellError};
+use chrono::{
+    Datelike, Local, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Timelike, Weekday,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use regex::Regex;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::thread;
 use std::time::Duration;
 
-/// Evaluate a simple binary operation.  
-/// Returns `Some(result)` or `None` if the operator is invalid or if division by zero is attempted.
-pub fn eval_binary(op: i8, a: i32, b: i32) -> Option<i32> {
-    match op {
-        1 => Some(a + b),
-        2 => Some(a - b),
-        3 => Some(a * b),
-        5 => {
-            if b == 0 {
-                None
+/// Evaluate a simple binary operation.
+/// Two integers (or booleans, as 1/0) stay an integer unless a division leaves a
+/// remainder; anything involving a decimal is computed in floating point.
+/// A date plus or minus a number of days is a date, and the difference of two
+/// dates is a number of days.
+/// Comparisons give a boolean and `&` joins both sides as text.
+/// An error on either side is passed through (left side first); text in
+/// arithmetic gives `#VALUE!`, division by zero gives `#DIV/0!` and a result
+/// out of range gives an overflow error.
+pub fn eval_binary(op: BinOp, a: &Cell, b: &Cell) -> Cell {
+    if a.is_error() {
+        return a.clone();
+    }
+    if b.is_error() {
+        return b.clone();
+    }
+    let ord = match op {
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Pow => {
+            return arithmetic(op, a, b)
+        }
+        BinOp::Concat => return Cell::Text(to_text(a) + &to_text(b)),
+        _ => compare(a, b),
+    };
+    Cell::Bool(match op {
+        BinOp::Eq => ord.is_eq(),
+        BinOp::Ne => ord.is_ne(),
+        BinOp::Lt => ord.is_lt(),
+        BinOp::Le => ord.is_le(),
+        BinOp::Gt => ord.is_gt(),
+        _ => ord.is_ge(),
+    })
+}
+
+fn arithmetic(op: BinOp, a: &Cell, b: &Cell) -> Cell {
+    match (op, a, b) {
+        (BinOp::Sub, Cell::Date(x), Cell::Date(y)) => {
+            let secs = (*x - *y).num_seconds();
+            return if secs % 86400 == 0 {
+                checked(i32::try_from(secs / 86400).ok())
             } else {
-                Some(a / b)
+                finite(secs as f64 / 86400.0)
+            };
+        }
+        (BinOp::Add | BinOp::Sub, Cell::Date(d), n) | (BinOp::Add, n, Cell::Date(d))
+            if !matches!(n, Cell::Date(_)) =>
+        {
+            let Some(days) = n.as_f64() else {
+                return Cell::Err(CellError::Value);
+            };
+            let days = if op == BinOp::Sub { -days } else { days };
+            return serial_date(date_serial(*d) + days)
+                .map_or(Cell::Err(CellError::Overflow), Cell::Date);
+        }
+        _ => {}
+    }
+    match (a.as_int(), b.as_int()) {
+        (Some(x), Some(y)) => match op {
+            BinOp::Add => checked(x.checked_add(y)),
+            BinOp::Sub => checked(x.checked_sub(y)),
+            BinOp::Mul => checked(x.checked_mul(y)),
+            BinOp::Pow if y >= 0 => checked(x.checked_pow(y as u32)),
+            BinOp::Pow => power(x as f64, y as f64),
+            _ => {
+                if y == 0 {
+                    return Cell::Err(CellError::DivZero);
+                }
+                match x.checked_rem(y) {
+                    Some(0) => checked(x.checked_div(y)),
+                    Some(_) => finite(x as f64 / y as f64),
+                    // i32::MIN / -1
+                    None => Cell::Err(CellError::Overflow),
+                }
+            }
+        },
+        _ => {
+            let (x, y) = match (a.as_f64(), b.as_f64()) {
+                (Some(x), Some(y)) => (x, y),
+                _ => return Cell::Err(CellError::Value),
+            };
+            match op {
+                BinOp::Add => finite(x + y),
+                BinOp::Sub => finite(x - y),
+                BinOp::Mul => finite(x * y),
+                BinOp::Pow => power(x, y),
+                _ => {
+                    if y == 0.0 {
+                        Cell::Err(CellError::DivZero)
+                    } else {
+                        finite(x / y)
+                    }
+                }
             }
         }
-        _ => None,
     }
 }
 
-/// Calculate the minimum value in the specified range.
-/// Returns `None` if any cell is in an error state.
-pub fn min_range<F>(
-    start: (u16, u16),
-    end: (u16, u16),
-    get_val: F,
-) -> Option<i32>
+/// Order two non-error values: numbers sort before text, and text before
+/// booleans.  Text compares without regard to case.
+fn compare(a: &Cell, b: &Cell) -> Ordering {
+    match (a, b) {
+        (Cell::Text(x), Cell::Text(y)) => x.to_lowercase().cmp(&y.to_lowercase()),
+        (Cell::Bool(x), Cell::Bool(y)) => x.cmp(y),
+        _ => match (a.as_f64(), b.as_f64()) {
+            (Some(x), Some(y)) if rank(a) == rank(b) => {
+                x.partial_cmp(&y).unwrap_or(Ordering::Equal)
+            }
+            _ => rank(a).cmp(&rank(b)),
+        },
+    }
+}
+
+/// Raise `x` to the power `y`.  Zero to a negative power is a division by
+/// zero, and a negative number to a fractional power has no real value.
+fn power(x: f64, y: f64) -> Cell {
+    if x == 0.0 && y < 0.0 {
+        return Cell::Err(CellError::DivZero);
+    }
+    let v = x.powf(y);
+    if v.is_nan() {
+        Cell::Err(CellError::Num)
+    } else {
+        finite(v)
+    }
+}
+
+/// Round to 15 significant digits, the precision spreadsheets work to, so that
+/// a value such as `2.675 * 100` is treated as the 267.5 it was meant to be.
+fn significant(v: f64) -> f64 {
+    format!("{:.14e}", v).parse().unwrap_or(v)
+}
+
+/// An integer cell when `whole` is set and the value fits, otherwise a decimal.
+fn whole_or_float(v: f64, whole: bool) -> Cell {
+    if whole && v.fract() == 0.0 && v >= i32::MIN as f64 && v <= i32::MAX as f64 {
+        Cell::Value(v as i32)
+    } else {
+        finite(v)
+    }
+}
+
+/// The text form of a non-error value, as `&` and the text functions see it.
+/// Decimals are written to 15 significant digits so that `0.1+0.2` reads "0.3".
+pub fn to_text(v: &Cell) -> String {
+    match v {
+        Cell::Value(v) => v.to_string(),
+        Cell::Float(v) => {
+            let magnitude = if *v == 0.0 {
+                0
+            } else {
+                v.abs().log10().floor() as i32
+            };
+            let decimals = (14 - magnitude).clamp(0, 15) as usize;
+            let text = format!("{:.*}", decimals, v);
+            if text.contains('.') {
+                text.trim_end_matches('0').trim_end_matches('.').to_string()
+            } else {
+                text
+            }
+        }
+        Cell::Text(t) => t.clone(),
+        Cell::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+        Cell::Date(d) => format_date(*d),
+        Cell::Err(e) => e.code().to_string(),
+    }
+}
+
+/// The sort group of a non-error value: numbers, then text, then booleans.
+fn rank(c: &Cell) -> u8 {
+    match c {
+        Cell::Text(_) => 1,
+        Cell::Bool(_) => 2,
+        _ => 0,
+    }
+}
+
+/// Wrap a checked integer result, turning `None` into an overflow error.
+fn checked(v: Option<i32>) -> Cell {
+    v.map_or(Cell::Err(CellError::Overflow), Cell::Value)
+}
+
+/// Wrap a decimal result, turning an infinite or NaN result into an overflow error.
+fn finite(v: f64) -> Cell {
+    if v.is_finite() {
+        Cell::Float(v)
+    } else {
+        Cell::Err(CellError::Overflow)
+    }
+}
+
+/// Evaluate a parsed formula.
+/// The callback returns the current contents of each referenced cell, or `None`
+/// for a cell that was never set, which reads as 0.
+/// Returns an error cell if any operand is in an error state, a division by zero
+/// occurs or a result overflows.
+pub fn eval_expr<F>(expr: &Expr, get_val: &F) -> Cell
 where
-    F: Fn((u16, u16)) -> Option<i32>,
+    F: Fn((u16, u16)) -> Option<Cell>,
 {
-    let mut min_val = i32::MAX;
-    for c in start.0..=end.0 {
-        for r in start.1..=end.1 {
-            let v = get_val((c, r))?;
-            if v < min_val {
-                min_val = v;
-            }
+    match expr {
+        Expr::Number(v) => Cell::Value(*v),
+        Expr::Float(v) => Cell::Float(*v),
+        Expr::Text(s) => Cell::Text(s.clone()),
+        Expr::Bool(b) => Cell::Bool(*b),
+        Expr::Date(d) => Cell::Date(*d),
+        Expr::Error(e) => Cell::Err(*e),
+        Expr::Pattern(p) => Cell::Text(p.0.as_str().to_string()),
+        Expr::Ref(r) => value_at(get_val, r.coord),
+        Expr::Neg(inner) => match eval_expr(inner, get_val) {
+            Cell::Value(v) => checked(v.checked_neg()),
+            Cell::Float(v) => Cell::Float(-v),
+            Cell::Bool(b) => Cell::Value(-(b as i32)),
+            Cell::Date(d) => finite(-date_serial(d)),
+            Cell::Text(_) => Cell::Err(CellError::Value),
+            err => err,
+        },
+        Expr::Binary(op, lhs, rhs) => {
+            let a = eval_expr(lhs, get_val);
+            let b = eval_expr(rhs, get_val);
+            eval_binary(*op, &a, &b)
         }
+        Expr::Call(func, args) => eval_call(func, args, get_val),
+        // A bare range has no single value.
+        Expr::Range(..) => Cell::Err(CellError::Value),
     }
-    Some(min_val)
 }
 
-/// Calculate the maximum value in the specified range.
-/// Returns `None` if any cell is in an error state.
-pub fn max_range<F>(
-    start: (u16, u16),
-    end: (u16, u16),
-    get_val: F,
-) -> Option<i32>
+/// The shape of the arguments a function accepts.
+pub struct Signature {
+    pub min_args: usize,
+    pub max_args: usize,
+    /// Whether a range such as `A1:B3` may be passed as an argument.
+    pub ranges: bool,
+}
+
+/// Look up the signature of a built-in function, or `None` if `func` is unknown.
+pub fn signature(func: &str) -> Option<Signature> {
+    let (min_args, max_args, ranges) = match func {
+        "SLEEP" | "ERROR.TYPE" | "NOT" | "ISERROR" | "YEAR" | "MONTH" | "DAY" => (1, 1, false),
+        "TODAY" | "NOW" | "RAND" => (0, 0, false),
+        "RANDBETWEEN" => (2, 2, false),
+        "PMT" | "PV" | "FV" | "NPER" => (3, 5, false),
+        "RATE" => (3, 6, false),
+        "NPV" => (2, usize::MAX, true),
+        "ABS" | "SQRT" | "LN" | "EXP" => (1, 1, false),
+        "ROUND" | "ROUNDUP" | "ROUNDDOWN" | "FLOOR" | "CEILING" => (1, 2, false),
+        "MOD" | "POWER" => (2, 2, false),
+        "IRR" => (1, 2, true),
+        "DATE" | "DATEDIF" => (3, 3, false),
+        "WEEKDAY" => (1, 2, false),
+        "EDATE" | "EOMONTH" => (2, 2, false),
+        "NETWORKDAYS" => (2, 3, true),
+        "LEN" | "UPPER" | "LOWER" | "TRIM" => (1, 1, false),
+        "LEFT" | "RIGHT" => (1, 2, false),
+        "MID" => (3, 3, false),
+        "SUBSTITUTE" => (3, 4, false),
+        "TEXT" | "REGEXMATCH" | "REGEXEXTRACT" => (2, 2, false),
+        "REGEXREPLACE" => (3, 3, false),
+        "CONCAT" => (1, usize::MAX, true),
+        "IF" => (2, 3, false),
+        "IFERROR" => (2, 2, false),
+        "COUNTIF" | "PERCENTILE" | "QUARTILE" | "CORREL" => (2, 2, true),
+        "SUMIF" | "AVERAGEIF" | "INDEX" | "MATCH" => (2, 3, true),
+        "VLOOKUP" | "HLOOKUP" => (3, 4, true),
+        "XLOOKUP" => (3, 5, true),
+        "AND" | "OR" | "COUNT" | "COUNTA" | "MIN" | "MAX" | "AVG" | "SUM" | "STDEV" | "STDEVP"
+        | "STDEV.S" | "VAR" | "VARP" | "MEDIAN" | "MODE" => (1, usize::MAX, true),
+        _ => return None,
+    };
+    Some(Signature {
+        min_args,
+        max_args,
+        ranges,
+    })
+}
+
+/// Whether a function gives a new result each time it is evaluated, so that a
+/// cell calling it must be recalculated after every edit.
+pub fn is_volatile(func: &str) -> bool {
+    matches!(func, "RAND" | "RANDBETWEEN" | "TODAY" | "NOW")
+}
+
+thread_local! {
+    /// The source for RAND and RANDBETWEEN while a cell is being evaluated.
+    static RANDOM: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with RAND and RANDBETWEEN drawing from a generator seeded with
+/// `seed`, so that the same seed gives the same numbers.
+pub fn with_seed<R>(seed: u64, f: impl FnOnce() -> R) -> R {
+    let previous = RANDOM.with(|r| r.replace(Some(StdRng::seed_from_u64(seed))));
+    let result = f();
+    RANDOM.with(|r| *r.borrow_mut() = previous);
+    result
+}
+
+/// A random number in `range`, drawn from the generator `with_seed` set up.
+fn random<T, R>(range: R) -> T
+where
+    T: rand::distributions::uniform::SampleUniform,
+    R: rand::distributions::uniform::SampleRange<T>,
+{
+    RANDOM.with(|r| {
+        r.borrow_mut()
+            .get_or_insert_with(StdRng::from_entropy)
+            .gen_range(range)
+    })
+}
+
+/// Evaluate a function call.  Arguments are evaluated on demand through `get_val`,
+/// so `IF` and `IFERROR` never evaluate the branch they do not take.
+/// Returns the first error if any argument that is used is in an error state.
+pub fn eval_call<F>(func: &str, args: &[Expr], get_val: &F) -> Cell
 where
-    F: Fn((u16, u16)) -> Option<i32>,
+    F: Fn((u16, u16)) -> Option<Cell>,
 {
-    let mut max_val = i32::MIN;
-    for c in start.0..=end.0 {
-        for r in start.1..=end.1 {
-            let v = get_val((c, r))?;
-            if v > max_val {
-                max_val = v;
+    match (func, args) {
+        ("SLEEP", [arg]) => {
+            let sec = eval_expr(arg, get_val);
+            if let Some(s) = sec.as_f64() {
+                if s > 0.0 {
+                    // Too long to represent as a Duration
+                    let Ok(duration) = Duration::try_from_secs_f64(s) else {
+                        return Cell::Err(CellError::Num);
+                    };
+                    thread::sleep(duration);
+                }
+            }
+            sec
+        }
+        ("ERROR.TYPE", [arg]) => match eval_expr(arg, get_val) {
+            Cell::Err(e) => Cell::Value(e.type_number()),
+            _ => Cell::Err(CellError::NA),
+        },
+        ("IF", [cond, then, rest @ ..]) => match truth(&eval_expr(cond, get_val)) {
+            Ok(true) => eval_expr(then, get_val),
+            Ok(false) => match rest {
+                [otherwise] => eval_expr(otherwise, get_val),
+                _ => Cell::Bool(false),
+            },
+            Err(e) => e,
+        },
+        ("IFERROR", [value, fallback]) => match eval_expr(value, get_val) {
+            v if v.is_error() => eval_expr(fallback, get_val),
+            v => v,
+        },
+        ("ISERROR", [value]) => Cell::Bool(eval_expr(value, get_val).is_error()),
+        ("NOT", [value]) => match truth(&eval_expr(value, get_val)) {
+            Ok(b) => Cell::Bool(!b),
+            Err(e) => e,
+        },
+        ("AND" | "OR", _) => {
+            let is_and = func == "AND";
+            let mut result = is_and;
+            let mut seen = false;
+            for (v, from_range) in arg_values(args, get_val) {
+                let b = match truth(&v) {
+                    Ok(b) => b,
+                    // Text inside a range is ignored
+                    Err(_) if from_range && matches!(v, Cell::Text(_)) => continue,
+                    Err(e) => return e,
+                };
+                seen = true;
+                result = if is_and { result && b } else { result || b };
+            }
+            if seen {
+                Cell::Bool(result)
+            } else {
+                Cell::Err(CellError::Value)
+            }
+        }
+        ("COUNT", _) => {
+            // Numbers, plus booleans given directly; text and errors are not counted
+            let count = arg_values(args, get_val)
+                .iter()
+                .filter(|(v, from_range)| match v {
+                    Cell::Value(_) | Cell::Float(_) | Cell::Date(_) => true,
+                    Cell::Bool(_) => !from_range,
+                    _ => false,
+                })
+                .count();
+            Cell::Value(count as i32)
+        }
+        // Ranges only yield the cells that have been set, whatever they hold
+        ("COUNTA", _) => Cell::Value(arg_values(args, get_val).len() as i32),
+        ("PERCENTILE" | "QUARTILE", [data, k]) => {
+            let values = match numbers(arg_values(std::slice::from_ref(data), get_val)) {
+                Ok(values) => values,
+                Err(e) => return e,
+            };
+            let k = match eval_expr(k, get_val) {
+                Cell::Text(_) => return Cell::Err(CellError::Value),
+                v if v.is_error() => return v,
+                v => v.as_f64().unwrap_or_default(),
+            };
+            if func == "QUARTILE" {
+                // QUARTILE(data, q) is PERCENTILE(data, q/4) for q = 0..=4
+                if !(0.0..5.0).contains(&k) {
+                    return Cell::Err(CellError::Num);
+                }
+                percentile_values(&values, k.trunc() / 4.0)
+            } else {
+                percentile_values(&values, k)
+            }
+        }
+        ("CORREL", [xs, ys]) => {
+            // Cells are paired by position, so empty ones are dropped in pairs
+            let cells = |arg: &Expr| match arg {
+                Expr::Range(start, end) => {
+                    cells_in((start.coord, end.coord)).map(get_val).collect()
+                }
+                _ => vec![Some(eval_expr(arg, get_val))],
+            };
+            let (xs, ys): (Vec<_>, Vec<_>) = (cells(xs), cells(ys));
+            if xs.len() != ys.len() {
+                return Cell::Err(CellError::NA);
+            }
+            correl_values(xs.into_iter().zip(ys).filter_map(|(x, y)| Some((x?, y?))))
+        }
+        ("VLOOKUP" | "HLOOKUP", [key, table, index, rest @ ..]) => {
+            let key = eval_expr(key, get_val);
+            if key.is_error() {
+                return key;
+            }
+            let Some((start, end)) = area(table) else {
+                return Cell::Err(CellError::Value);
+            };
+            let index = match int_arg(index, get_val) {
+                Ok(i) => i,
+                Err(e) => return e,
+            };
+            let approximate = match rest.first().map(|a| truth(&eval_expr(a, get_val))) {
+                Some(Err(e)) => return e,
+                Some(Ok(b)) => b,
+                None => true,
+            };
+            let mode = if approximate {
+                MatchMode::Below
+            } else {
+                MatchMode::Exact
+            };
+            // Search the first column (VLOOKUP) or row (HLOOKUP), then step
+            // `index` columns or rows across from the match.
+            let (vertical, width) = if func == "VLOOKUP" {
+                (true, end.0 - start.0 + 1)
+            } else {
+                (false, end.1 - start.1 + 1)
+            };
+            if index < 1 {
+                return Cell::Err(CellError::Value);
+            }
+            if index > width as i64 {
+                return Cell::Err(CellError::Ref);
+            }
+            let offset = index as u16 - 1;
+            let line = if vertical {
+                (start, (start.0, end.1))
+            } else {
+                (start, (end.0, start.1))
+            };
+            let keys: Vec<Cell> = cells_in(line).map(|c| value_at(get_val, c)).collect();
+            match find(&key, &keys, mode) {
+                Some(i) if vertical => value_at(get_val, (start.0 + offset, start.1 + i as u16)),
+                Some(i) => value_at(get_val, (start.0 + i as u16, start.1 + offset)),
+                None => Cell::Err(CellError::NA),
+            }
+        }
+        ("INDEX", [table, row, rest @ ..]) => {
+            let Some((start, end)) = area(table) else {
+                return Cell::Err(CellError::Value);
+            };
+            let (cols, rows) = (end.0 - start.0 + 1, end.1 - start.1 + 1);
+            let mut indices = Vec::new();
+            for arg in std::iter::once(row).chain(rest) {
+                match int_arg(arg, get_val) {
+                    Ok(i) => indices.push(i),
+                    Err(e) => return e,
+                }
+            }
+            // A single index counts along a one-row or one-column range.
+            let (r, c) = match indices[..] {
+                [i] if rows == 1 => (1, i),
+                [i] if cols == 1 => (i, 1),
+                [r, c] => (r, c),
+                _ => return Cell::Err(CellError::Value),
+            };
+            if r < 1 || c < 1 || r > rows as i64 || c > cols as i64 {
+                return Cell::Err(CellError::Ref);
+            }
+            value_at(get_val, (start.0 + c as u16 - 1, start.1 + r as u16 - 1))
+        }
+        ("MATCH", [key, range, rest @ ..]) => {
+            let key = eval_expr(key, get_val);
+            if key.is_error() {
+                return key;
+            }
+            let Some(line) = area(range).filter(|&a| shape(a).0 == 0 || shape(a).1 == 0) else {
+                return Cell::Err(CellError::NA);
+            };
+            let mode = match rest.first().map(|a| int_arg(a, get_val)) {
+                Some(Err(e)) => return e,
+                Some(Ok(0)) => MatchMode::Exact,
+                Some(Ok(t)) if t < 0 => MatchMode::Above,
+                _ => MatchMode::Below,
+            };
+            let values: Vec<Cell> = cells_in(line).map(|c| value_at(get_val, c)).collect();
+            match find(&key, &values, mode) {
+                Some(i) => Cell::Value(i as i32 + 1),
+                None => Cell::Err(CellError::NA),
+            }
+        }
+        ("XLOOKUP", [key, lookup, result, rest @ ..]) => {
+            let key = eval_expr(key, get_val);
+            if key.is_error() {
+                return key;
+            }
+            let one_dimensional = |a: &RangeRef| shape(*a).0 == 0 || shape(*a).1 == 0;
+            let (Some(lookup), Some(result)) = (
+                area(lookup).filter(one_dimensional),
+                area(result).filter(one_dimensional),
+            ) else {
+                return Cell::Err(CellError::Value);
+            };
+            let keys: Vec<Cell> = cells_in(lookup).map(|c| value_at(get_val, c)).collect();
+            let results: Vec<(u16, u16)> = cells_in(result).collect();
+            if keys.len() != results.len() {
+                return Cell::Err(CellError::Value);
+            }
+            let mode = match rest.get(1).map(|a| int_arg(a, get_val)) {
+                Some(Err(e)) => return e,
+                None | Some(Ok(0)) => MatchMode::Exact,
+                Some(Ok(-1)) => MatchMode::Below,
+                Some(Ok(1)) => MatchMode::Above,
+                Some(Ok(_)) => return Cell::Err(CellError::Value),
+            };
+            match (find(&key, &keys, mode), rest.first()) {
+                (Some(i), _) => value_at(get_val, results[i]),
+                (None, Some(if_not_found)) => eval_expr(if_not_found, get_val),
+                (None, None) => Cell::Err(CellError::NA),
+            }
+        }
+        ("RAND", []) => Cell::Float(random(0.0..1.0)),
+        ("RANDBETWEEN", [lo, hi]) => match (int_arg(lo, get_val), int_arg(hi, get_val)) {
+            (Ok(lo), Ok(hi)) if lo <= hi => checked(i32::try_from(random(lo..=hi)).ok()),
+            (Ok(_), Ok(_)) => Cell::Err(CellError::Num),
+            (Err(e), _) | (_, Err(e)) => e,
+        },
+        ("TODAY", []) => Cell::Date(Local::now().date_naive().and_time(NaiveTime::MIN)),
+        ("NOW", []) => Cell::Date(
+            Local::now()
+                .naive_local()
+                .with_nanosecond(0)
+                .unwrap_or_default(),
+        ),
+        ("DATE", [year, month, day]) => {
+            let mut parts = Vec::new();
+            for arg in [year, month, day] {
+                match int_arg(arg, get_val) {
+                    Ok(v) => parts.push(v),
+                    Err(e) => return e,
+                }
+            }
+            // Months and days past the end roll over, as in DATE(2024, 14, 1);
+            // values too large for any date give #NUM!
+            let date = || {
+                let months = parts[0]
+                    .checked_mul(12)?
+                    .checked_add(parts[1].checked_sub(1)?)?;
+                let year = i32::try_from(months.div_euclid(12)).ok()?;
+                let first = NaiveDate::from_ymd_opt(year, months.rem_euclid(12) as u32 + 1, 1)?;
+                first.checked_add_signed(TimeDelta::try_days(parts[2].checked_sub(1)?)?)
+            };
+            date().map_or(Cell::Err(CellError::Num), |d| {
+                Cell::Date(d.and_time(NaiveTime::MIN))
+            })
+        }
+        ("YEAR" | "MONTH" | "DAY" | "WEEKDAY", [date, rest @ ..]) => {
+            let date = match date_arg(date, get_val) {
+                Ok(d) => d,
+                Err(e) => return e,
+            };
+            let part = match func {
+                "YEAR" => date.year() as u32,
+                "MONTH" => date.month(),
+                "DAY" => date.day(),
+                _ => {
+                    let weekday = date.weekday();
+                    match rest.first().map(|a| int_arg(a, get_val)) {
+                        Some(Err(e)) => return e,
+                        None | Some(Ok(1)) => weekday.num_days_from_sunday() + 1,
+                        Some(Ok(2)) => weekday.number_from_monday(),
+                        Some(Ok(3)) => weekday.num_days_from_monday(),
+                        Some(Ok(_)) => return Cell::Err(CellError::Num),
+                    }
+                }
+            };
+            Cell::Value(part as i32)
+        }
+        ("EDATE" | "EOMONTH", [date, months]) => {
+            let (date, months) = match (date_arg(date, get_val), int_arg(months, get_val)) {
+                (Ok(d), Ok(m)) => (d.date(), m),
+                (Err(e), _) | (_, Err(e)) => return e,
+            };
+            let shifted = if func == "EDATE" {
+                add_months(date, months)
+            } else {
+                // The day before the first of the following month
+                date.with_day(1)
+                    .and_then(|first| add_months(first, months.checked_add(1)?))
+                    .and_then(|next| next.pred_opt())
+            };
+            shifted.map_or(Cell::Err(CellError::Num), |d| {
+                Cell::Date(d.and_time(NaiveTime::MIN))
+            })
+        }
+        ("DATEDIF", [start, end, unit]) => {
+            let (start, end) = match (date_arg(start, get_val), date_arg(end, get_val)) {
+                (Ok(s), Ok(e)) => (s.date(), e.date()),
+                (Err(e), _) | (_, Err(e)) => return e,
+            };
+            match eval_expr(unit, get_val) {
+                Cell::Text(unit) => datedif(start, end, &unit.to_uppercase()),
+                v if v.is_error() => v,
+                _ => Cell::Err(CellError::Value),
+            }
+        }
+        ("NETWORKDAYS", [start, end, rest @ ..]) => {
+            let (start, end) = match (date_arg(start, get_val), date_arg(end, get_val)) {
+                (Ok(s), Ok(e)) => (s.date(), e.date()),
+                (Err(e), _) | (_, Err(e)) => return e,
+            };
+            let mut holidays = Vec::new();
+            for (v, _) in arg_values(rest, get_val) {
+                match v {
+                    Cell::Err(_) => return v,
+                    Cell::Text(_) => {}
+                    v => match v.as_f64().and_then(serial_date) {
+                        Some(d) => holidays.push(d.date()),
+                        None => return Cell::Err(CellError::Num),
+                    },
+                }
+            }
+            let (first, last, sign) = if start <= end {
+                (start, end, 1)
+            } else {
+                (end, start, -1)
+            };
+            let days = first
+                .iter_days()
+                .take_while(|d| *d <= last)
+                .filter(|d| !matches!(d.weekday(), Weekday::Sat | Weekday::Sun))
+                .filter(|d| !holidays.contains(d))
+                .count();
+            Cell::Value(sign * days as i32)
+        }
+        ("CONCAT", _) => {
+            let mut text = String::new();
+            for (v, _) in arg_values(args, get_val) {
+                if v.is_error() {
+                    return v;
+                }
+                text += &to_text(&v);
+            }
+            Cell::Text(text)
+        }
+        ("LEN" | "UPPER" | "LOWER" | "TRIM", [text]) => {
+            let text = match text_arg(text, get_val) {
+                Ok(t) => t,
+                Err(e) => return e,
+            };
+            match func {
+                "LEN" => Cell::Value(text.chars().count() as i32),
+                "UPPER" => Cell::Text(text.to_uppercase()),
+                "LOWER" => Cell::Text(text.to_lowercase()),
+                // Drop spaces at either end and squeeze runs of spaces inside
+                _ => Cell::Text(
+                    text.split(' ')
+                        .filter(|w| !w.is_empty())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+            }
+        }
+        ("LEFT" | "RIGHT" | "MID", [text, rest @ ..]) => {
+            let text = match text_arg(text, get_val) {
+                Ok(t) => t,
+                Err(e) => return e,
+            };
+            let mut numbers = Vec::new();
+            for arg in rest {
+                match int_arg(arg, get_val) {
+                    Ok(n) if n >= 0 => numbers.push(n as usize),
+                    Ok(_) => return Cell::Err(CellError::Value),
+                    Err(e) => return e,
+                }
+            }
+            let chars: Vec<char> = text.chars().collect();
+            let (from, count) = match (func, &numbers[..]) {
+                ("MID", &[0, _]) => return Cell::Err(CellError::Value),
+                ("MID", &[start, count]) => (start - 1, count),
+                ("LEFT", _) => (0, numbers.first().copied().unwrap_or(1)),
+                _ => {
+                    let count = numbers.first().copied().unwrap_or(1).min(chars.len());
+                    (chars.len() - count, count)
+                }
+            };
+            Cell::Text(chars.iter().skip(from).take(count).collect())
+        }
+        ("SUBSTITUTE", [text, old, new, rest @ ..]) => {
+            let mut parts = Vec::new();
+            for arg in [text, old, new] {
+                match text_arg(arg, get_val) {
+                    Ok(t) => parts.push(t),
+                    Err(e) => return e,
+                }
+            }
+            let (text, old, new) = (&parts[0], &parts[1], &parts[2]);
+            if old.is_empty() {
+                return Cell::Text(text.clone());
+            }
+            match rest.first().map(|a| int_arg(a, get_val)) {
+                None => Cell::Text(text.replace(old.as_str(), new)),
+                Some(Ok(n)) if n >= 1 => match text.match_indices(old.as_str()).nth(n as usize - 1)
+                {
+                    Some((at, _)) => {
+                        Cell::Text(format!("{}{}{}", &text[..at], new, &text[at + old.len()..]))
+                    }
+                    None => Cell::Text(text.clone()),
+                },
+                Some(Ok(_)) => Cell::Err(CellError::Value),
+                Some(Err(e)) => e,
+            }
+        }
+        ("TEXT", [value, format]) => {
+            let format = match text_arg(format, get_val) {
+                Ok(f) => f,
+                Err(e) => return e,
+            };
+            match eval_expr(value, get_val) {
+                v if v.is_error() => v,
+                Cell::Text(t) => Cell::Text(t),
+                Cell::Date(d) => Cell::Text(format_date_as(d, &format)),
+                v if is_date_format(&format) => match v.as_f64().and_then(serial_date) {
+                    Some(d) => Cell::Text(format_date_as(d, &format)),
+                    None => Cell::Err(CellError::Num),
+                },
+                v => match format_number_as(v.as_f64().unwrap_or_default(), &format) {
+                    Some(text) => Cell::Text(text),
+                    None => Cell::Err(CellError::Num),
+                },
+            }
+        }
+        ("REGEXMATCH" | "REGEXEXTRACT" | "REGEXREPLACE", [text, pattern, rest @ ..]) => {
+            let text = match text_arg(text, get_val) {
+                Ok(t) => t,
+                Err(e) => return e,
+            };
+            // Literal patterns were compiled with the formula
+            let re = match pattern {
+                Expr::Pattern(p) => p.0.clone(),
+                _ => match text_arg(pattern, get_val).map(|p| compiled_regex(&p)) {
+                    Ok(Some(re)) => re,
+                    Ok(None) => return Cell::Err(CellError::Value),
+                    Err(e) => return e,
+                },
+            };
+            let replacement = match rest.first().map(|a| text_arg(a, get_val)) {
+                Some(Err(e)) => return e,
+                Some(Ok(r)) => r,
+                None => String::new(),
+            };
+            match func {
+                "REGEXMATCH" => Cell::Bool(re.is_match(&text)),
+                // The first capture group if there is one, else the whole match
+                "REGEXEXTRACT" => match re.captures(&text) {
+                    Some(caps) => {
+                        let m = caps.get(1).or_else(|| caps.get(0));
+                        Cell::Text(m.map_or("", |m| m.as_str()).to_string())
+                    }
+                    None => Cell::Err(CellError::NA),
+                },
+                _ => Cell::Text(re.replace_all(&text, replacement.as_str()).into_owned()),
+            }
+        }
+        ("PMT" | "PV" | "FV" | "NPER" | "RATE", _) => {
+            let mut x = Vec::new();
+            for arg in args {
+                match num_arg(arg, get_val) {
+                    Ok(v) => x.push(v),
+                    Err(e) => return e,
+                }
+            }
+            time_value(func, &x)
+        }
+        ("NPV", [rate, flows @ ..]) => {
+            let rate = match num_arg(rate, get_val) {
+                Ok(r) => r,
+                Err(e) => return e,
+            };
+            match numbers(arg_values(flows, get_val)) {
+                Ok(flows) => finite(npv(rate, &flows, 1)),
+                Err(e) => e,
+            }
+        }
+        ("IRR", [flows, rest @ ..]) => {
+            let flows = match numbers(arg_values(std::slice::from_ref(flows), get_val)) {
+                Ok(flows) => flows,
+                Err(e) => return e,
+            };
+            let guess = match rest.first().map(|g| num_arg(g, get_val)) {
+                Some(Err(e)) => return e,
+                Some(Ok(g)) => g,
+                None => 0.1,
+            };
+            // Without both an outflow and an inflow there is no rate of return
+            let signs = flows.iter().filter_map(Cell::as_f64);
+            if !signs.clone().any(|v| v > 0.0) || !signs.clone().any(|v| v < 0.0) {
+                return Cell::Err(CellError::Num);
+            }
+            solve(|r| npv(r, &flows, 0), guess).map_or(Cell::Err(CellError::Num), Cell::Float)
+        }
+        ("ABS", [x]) => match eval_expr(x, get_val) {
+            Cell::Value(v) => checked(v.checked_abs()),
+            Cell::Bool(b) => Cell::Value(b as i32),
+            Cell::Text(_) => Cell::Err(CellError::Value),
+            v if v.is_error() => v,
+            v => finite(v.as_f64().unwrap_or_default().abs()),
+        },
+        ("SQRT" | "LN" | "EXP", [x]) => {
+            let x = match num_arg(x, get_val) {
+                Ok(x) => x,
+                Err(e) => return e,
+            };
+            match func {
+                "SQRT" if x < 0.0 => Cell::Err(CellError::Num),
+                "SQRT" => finite(x.sqrt()),
+                "LN" if x <= 0.0 => Cell::Err(CellError::Num),
+                "LN" => finite(x.ln()),
+                _ => finite(x.exp()),
+            }
+        }
+        ("POWER", [x, y]) => {
+            let (x, y) = (eval_expr(x, get_val), eval_expr(y, get_val));
+            eval_binary(BinOp::Pow, &x, &y)
+        }
+        ("MOD", [x, y]) => match (eval_expr(x, get_val), eval_expr(y, get_val)) {
+            (e, _) | (_, e) if e.is_error() => e,
+            (x, y) => match (x.as_int(), y.as_int(), x.as_f64(), y.as_f64()) {
+                (_, Some(0), _, _) => Cell::Err(CellError::DivZero),
+                // The result takes the sign of the divisor
+                (Some(x), Some(y), _, _) => {
+                    Cell::Value(((x as i64 % y as i64 + y as i64) % y as i64) as i32)
+                }
+                (_, _, Some(_), Some(0.0)) => Cell::Err(CellError::DivZero),
+                (_, _, Some(x), Some(y)) => finite(x - y * significant(x / y).floor()),
+                _ => Cell::Err(CellError::Value),
+            },
+        },
+        ("ROUND" | "ROUNDUP" | "ROUNDDOWN" | "FLOOR" | "CEILING", [x, rest @ ..]) => {
+            let x = match eval_expr(x, get_val) {
+                Cell::Text(_) => return Cell::Err(CellError::Value),
+                v if v.is_error() => return v,
+                v => v,
+            };
+            let is_int = x.as_int().is_some();
+            let x = x.as_f64().unwrap_or_default();
+            let rounding = func.starts_with("ROUND");
+            let second = match rest.first().map(|a| num_arg(a, get_val)) {
+                Some(Err(e)) => return e,
+                Some(Ok(v)) => v,
+                None if rounding => 0.0,
+                None => 1.0,
+            };
+            if rounding {
+                // Round to `second` decimal places; a negative count rounds to tens, hundreds, ...
+                // The scale is kept within the range of a decimal.
+                let scale = 10f64.powi(second.trunc().clamp(-308.0, 308.0) as i32);
+                if !(x.abs() * scale).is_finite() {
+                    // More places than the value has digits: nothing to round
+                    return whole_or_float(x, is_int || second <= 0.0);
+                }
+                let scaled = significant(x.abs() * scale);
+                let rounded = match func {
+                    "ROUND" => scaled.round(),
+                    "ROUNDUP" => scaled.ceil(),
+                    _ => scaled.floor(),
+                };
+                whole_or_float(x.signum() * rounded / scale, is_int || second <= 0.0)
+            } else {
+                // Round to a multiple of `second`, the significance
+                let step = second;
+                if step == 0.0 {
+                    return if func == "FLOOR" {
+                        Cell::Err(CellError::DivZero)
+                    } else {
+                        Cell::Value(0)
+                    };
+                }
+                if x > 0.0 && step < 0.0 {
+                    return Cell::Err(CellError::Num);
+                }
+                let quotient = significant(x / step);
+                let multiple = if func == "FLOOR" {
+                    quotient.floor()
+                } else {
+                    quotient.ceil()
+                };
+                whole_or_float(multiple * step, is_int && step.fract() == 0.0)
+            }
+        }
+        ("COUNTIF", [range, criteria]) => eval_conditional(func, range, criteria, None, get_val),
+        ("SUMIF" | "AVERAGEIF", [range, criteria, rest @ ..]) => {
+            eval_conditional(func, range, criteria, rest.first(), get_val)
+        }
+        _ => match numbers(arg_values(args, get_val)) {
+            Ok(values) => eval_aggregate(func, &values),
+            Err(e) => e,
+        },
+    }
+}
+
+/// Interpret a value as a condition: any non-zero number is true.
+/// Text gives `#VALUE!` and errors are passed through.
+fn truth(v: &Cell) -> Result<bool, Cell> {
+    match v {
+        Cell::Err(_) => Err(v.clone()),
+        Cell::Text(_) => Err(Cell::Err(CellError::Value)),
+        _ => Ok(v.as_f64() != Some(0.0)),
+    }
+}
+
+/// The cells covered by a range or single-cell reference argument.
+fn area(arg: &Expr) -> Option<RangeRef> {
+    match arg {
+        Expr::Ref(r) => Some((r.coord, r.coord)),
+        Expr::Range(start, end) => Some((start.coord, end.coord)),
+        _ => None,
+    }
+}
+
+/// Every cell of an area, column by column.
+fn cells_in((start, end): RangeRef) -> impl Iterator<Item = (u16, u16)> {
+    (start.0..=end.0).flat_map(move |c| (start.1..=end.1).map(move |r| (c, r)))
+}
+
+/// The number of columns and rows an area spans, less one.
+fn shape((start, end): RangeRef) -> (u16, u16) {
+    (end.0 - start.0, end.1 - start.1)
+}
+
+/// A condition such as `">10"`, `"<>0"` or `"apples"` for COUNTIF and friends.
+struct Criterion {
+    op: BinOp,
+    value: Cell,
+}
+
+impl Criterion {
+    /// Build a criterion from an evaluated argument.  Text may start with a
+    /// comparison operator; the rest is read as a number, a boolean or text.
+    /// Any other value must be matched exactly.
+    fn new(arg: Cell) -> Result<Criterion, Cell> {
+        let text = match arg {
+            Cell::Err(_) => return Err(arg),
+            Cell::Text(text) => text,
+            value => {
+                return Ok(Criterion {
+                    op: BinOp::Eq,
+                    value,
+                })
             }
+        };
+        let (op, operand) = [
+            ("<=", BinOp::Le),
+            (">=", BinOp::Ge),
+            ("<>", BinOp::Ne),
+            ("<", BinOp::Lt),
+            (">", BinOp::Gt),
+            ("=", BinOp::Eq),
+        ]
+        .into_iter()
+        .find_map(|(prefix, op)| text.strip_prefix(prefix).map(|rest| (op, rest)))
+        .unwrap_or((BinOp::Eq, &text));
+        let value = if let Ok(v) = operand.trim().parse::<f64>() {
+            Cell::Float(v)
+        } else if operand.eq_ignore_ascii_case("TRUE") {
+            Cell::Bool(true)
+        } else if operand.eq_ignore_ascii_case("FALSE") {
+            Cell::Bool(false)
+        } else {
+            Cell::Text(operand.to_string())
+        };
+        Ok(Criterion { op, value })
+    }
+
+    /// Whether a cell meets the criterion.  Values of a different kind (text
+    /// against a number, say) only ever match `<>`, and errors never match.
+    fn matches(&self, v: &Cell) -> bool {
+        if v.is_error() {
+            false
+        } else if rank(v) != rank(&self.value) {
+            self.op == BinOp::Ne
+        } else {
+            eval_binary(self.op, v, &self.value) == Cell::Bool(true)
         }
     }
-    Some(max_val)
 }
 
-/// Calculate the average (rounded down) of values in the specified range.
-/// Returns `None` if any cell is in an error state, or `Some(0)` if the range is empty.
-pub fn avg_range<F>(
-    start: (u16, u16),
-    end: (u16, u16),
-    get_val: F,
-) -> Option<i32>
+/// Evaluate an argument that must be a number.  Text gives `#VALUE!` and
+/// errors are passed through.
+fn num_arg<F>(arg: &Expr, get_val: &F) -> Result<f64, Cell>
 where
-    F: Fn((u16, u16)) -> Option<i32>,
+    F: Fn((u16, u16)) -> Option<Cell>,
 {
-    let mut sum: i64 = 0;
-    let mut count: i64 = 0;
-    for c in start.0..=end.0 {
-        for r in start.1..=end.1 {
-            let v = get_val((c, r))?;
-            sum += v as i64;
-            count += 1;
+    match eval_expr(arg, get_val) {
+        v if v.is_error() => Err(v),
+        v => v.as_f64().ok_or(Cell::Err(CellError::Value)),
+    }
+}
+
+/// Evaluate an argument that must be a whole number, such as a column index.
+/// Decimals are truncated; text gives `#VALUE!` and errors are passed through.
+fn int_arg<F>(arg: &Expr, get_val: &F) -> Result<i64, Cell>
+where
+    F: Fn((u16, u16)) -> Option<Cell>,
+{
+    num_arg(arg, get_val).map(|x| x.trunc() as i64)
+}
+
+/// Evaluate an argument as text; numbers, booleans and dates are converted with
+/// `to_text` and errors are passed through.
+fn text_arg<F>(arg: &Expr, get_val: &F) -> Result<String, Cell>
+where
+    F: Fn((u16, u16)) -> Option<Cell>,
+{
+    match eval_expr(arg, get_val) {
+        v if v.is_error() => Err(v),
+        v => Ok(to_text(&v)),
+    }
+}
+
+/// Compile a pattern built from cell values, reusing the result for a pattern
+/// this thread has seen before.  Returns `None` for an invalid pattern.
+fn compiled_regex(pattern: &str) -> Option<Regex> {
+    const CACHE_LIMIT: usize = 1024;
+    thread_local! {
+        static CACHE: RefCell<HashMap<String, Regex>> = RefCell::new(HashMap::new());
+    }
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if let Some(re) = cache.get(pattern) {
+            return Some(re.clone());
+        }
+        let re = Regex::new(pattern).ok()?;
+        if cache.len() >= CACHE_LIMIT {
+            // Patterns from cell values could otherwise grow without bound
+            cache.clear();
+        }
+        cache.insert(pattern.to_string(), re.clone());
+        Some(re)
+    })
+}
+
+/// Whether a `TEXT` format is for dates (`yyyy-mm-dd`, `hh:mm`) rather than
+/// numbers (`0.00`, `#,##0`).
+fn is_date_format(format: &str) -> bool {
+    !format.contains(['0', '#'])
+        && format
+            .chars()
+            .any(|c| matches!(c.to_ascii_lowercase(), 'y' | 'm' | 'd' | 'h' | 's'))
+}
+
+/// Format a date with a spreadsheet-style pattern: `yyyy`/`yy` for the year,
+/// `m`/`mm`/`mmm`/`mmmm` for the month, `d`/`dd`/`ddd`/`dddd` for the day,
+/// `h`/`hh`, `mm` and `ss` for the time.  `m` and `mm` mean minutes right after
+/// an hour or right before seconds.  Other characters are copied as they are.
+fn format_date_as(date: NaiveDateTime, format: &str) -> String {
+    let chars: Vec<char> = format.chars().collect();
+    let mut out = String::new();
+    let mut after_hour = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i].to_ascii_lowercase();
+        let mut len = 1;
+        while i + len < chars.len() && chars[i + len].to_ascii_lowercase() == c {
+            len += 1;
+        }
+        let rest: String = chars[i + len..]
+            .iter()
+            .collect::<String>()
+            .to_ascii_lowercase();
+        let before_seconds = rest
+            .trim_start_matches(|c: char| !c.is_ascii_alphabetic())
+            .starts_with('s');
+        let piece = match (c, len) {
+            ('y', 1 | 2) => date.format("%y").to_string(),
+            ('y', _) => date.format("%Y").to_string(),
+            ('m', 1 | 2) if after_hour || before_seconds => date.format("%M").to_string(),
+            ('m', 1) => date.month().to_string(),
+            ('m', 2) => date.format("%m").to_string(),
+            ('m', 3) => date.format("%b").to_string(),
+            ('m', _) => date.format("%B").to_string(),
+            ('d', 1) => date.day().to_string(),
+            ('d', 2) => date.format("%d").to_string(),
+            ('d', 3) => date.format("%a").to_string(),
+            ('d', _) => date.format("%A").to_string(),
+            ('h', 1) => date.hour().to_string(),
+            ('h', _) => date.format("%H").to_string(),
+            ('s', _) => date.format("%S").to_string(),
+            _ => chars[i..i + len].iter().collect(),
+        };
+        if c.is_ascii_alphabetic() {
+            after_hour = c == 'h';
         }
+        out += &piece;
+        i += len;
     }
-    if count == 0 {
-        Some(0)
-    } else {
-        Some((sum / count) as i32)
+    out
+}
+
+/// Format a number with a spreadsheet-style pattern such as `0`, `0.00`,
+/// `#,##0.0` or `0%`.  `0` and `#` before the point set the least number of
+/// digits shown, those after it the number of decimals, a comma groups
+/// thousands and `%` multiplies by 100.  Text around the pattern is kept.
+/// Returns `None` when the scaled number is too large for a decimal.
+fn format_number_as(x: f64, format: &str) -> Option<String> {
+    let is_digit = |c: char| matches!(c, '0' | '#' | ',' | '.');
+    let (Some(first), Some(last)) = (format.find(is_digit), format.rfind(is_digit)) else {
+        return Some(format.to_string());
+    };
+    let (prefix, pattern, suffix) = (&format[..first], &format[first..=last], &format[last + 1..]);
+    let x = if format.contains('%') { x * 100.0 } else { x };
+    if !x.is_finite() {
+        return None;
+    }
+    let (int_pattern, frac_pattern) = pattern.split_once('.').unwrap_or((pattern, ""));
+    let decimals = frac_pattern
+        .chars()
+        .filter(|c| matches!(c, '0' | '#'))
+        .count();
+    let min_digits = int_pattern.chars().filter(|c| *c == '0').count();
+    let text = format!("{:.*}", decimals, x.abs());
+    let (int_digits, frac_digits) = text.split_once('.').unwrap_or((&text, ""));
+    let int_digits = int_digits.trim_start_matches('0');
+    let int_digits = format!("{:0>width$}", int_digits, width = min_digits);
+    let mut grouped = String::new();
+    for (i, c) in int_digits.chars().enumerate() {
+        if int_pattern.contains(',') && i > 0 && (int_digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    let negative = x < 0.0 && text.chars().any(|c| matches!(c, '1'..='9'));
+    let mut out = format!("{}{}{}", if negative { "-" } else { "" }, prefix, grouped);
+    if decimals > 0 {
+        out = out + "." + frac_digits;
     }
+    Some(out + suffix)
 }
 
-/// Calculate the sum of values in the specified range.
-/// Returns `None` if any cell is in an error state.
-pub fn sum_range<F>(
-    start: (u16, u16),
-    end: (u16, u16),
-    get_val: F,
-) -> Option<i32>
+/// Evaluate an argument that must be a date.  A number is read as a serial day
+/// number; text gives `#VALUE!` and errors are passed through.
+fn date_arg<F>(arg: &Expr, get_val: &F) -> Result<NaiveDateTime, Cell>
 where
-    F: Fn((u16, u16)) -> Option<i32>,
+    F: Fn((u16, u16)) -> Option<Cell>,
 {
-    let mut sum: i32 = 0;
-    for c in start.0..=end.0 {
-        for r in start.1..=end.1 {
-            let v = get_val((c, r))?;
-            sum += v;
+    match eval_expr(arg, get_val) {
+        Cell::Date(d) => Ok(d),
+        v if v.is_error() => Err(v),
+        Cell::Text(_) => Err(Cell::Err(CellError::Value)),
+        v => v
+            .as_f64()
+            .and_then(serial_date)
+            .ok_or(Cell::Err(CellError::Num)),
+    }
+}
+
+/// Move a date by whole months, keeping the day of the month where it exists
+/// and using the last day of the month where it does not.
+fn add_months(date: NaiveDate, months: i64) -> Option<NaiveDate> {
+    let step = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+    if months < 0 {
+        date.checked_sub_months(step)
+    } else {
+        date.checked_add_months(step)
+    }
+}
+
+/// The difference between two dates in the given unit: whole years ("Y"),
+/// months ("M") or days ("D"), or what is left over after whole years ("YM"
+/// months, "YD" days) or whole months ("MD" days).
+fn datedif(start: NaiveDate, end: NaiveDate, unit: &str) -> Cell {
+    if start > end {
+        return Cell::Err(CellError::Num);
+    }
+    let mut months =
+        (end.year() - start.year()) as i64 * 12 + end.month() as i64 - start.month() as i64;
+    if end.day() < start.day() {
+        months -= 1;
+    }
+    let days_after =
+        |whole_months: i64| add_months(start, whole_months).map(|anchor| (end - anchor).num_days());
+    let result = match unit {
+        "Y" => Some(months / 12),
+        "M" => Some(months),
+        "D" => Some((end - start).num_days()),
+        "YM" => Some(months % 12),
+        "YD" => days_after(months / 12 * 12),
+        "MD" => days_after(months),
+        _ => return Cell::Err(CellError::Num),
+    };
+    checked(result.and_then(|v| i32::try_from(v).ok()))
+}
+
+/// How a lookup treats a key that is not found exactly.
+#[derive(Clone, Copy)]
+enum MatchMode {
+    /// Only an exact match will do.
+    Exact,
+    /// Fall back to the largest value below the key.
+    Below,
+    /// Fall back to the smallest value above the key.
+    Above,
+}
+
+/// Find the position of `key` among `values`: the first exact match, or else
+/// the closest value on the side `mode` allows.  Only values of the same kind
+/// as the key are considered, and text is matched without regard to case.
+fn find(key: &Cell, values: &[Cell], mode: MatchMode) -> Option<usize> {
+    let candidates = values
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| !v.is_error() && rank(v) == rank(key));
+    let mut best: Option<(usize, &Cell)> = None;
+    for (i, v) in candidates {
+        let better = match compare(v, key) {
+            Ordering::Equal => return Some(i),
+            Ordering::Less => matches!(mode, MatchMode::Below),
+            Ordering::Greater => matches!(mode, MatchMode::Above),
+        } && best.is_none_or(|(_, b)| match mode {
+            MatchMode::Below => compare(v, b).is_gt(),
+            _ => compare(v, b).is_lt(),
+        });
+        if better {
+            best = Some((i, v));
+        }
+    }
+    best.map(|(i, _)| i)
+}
+
+/// The net present value of cash flows at `rate` per period, with the first
+/// flow discounted by `first_period` periods.
+fn npv(rate: f64, flows: &[Cell], first_period: i32) -> f64 {
+    flows
+        .iter()
+        .filter_map(Cell::as_f64)
+        .zip(first_period..)
+        .map(|(v, t)| v / (1.0 + rate).powi(t))
+        .sum()
+}
+
+/// Find a root of `f` near `guess` by Newton's method, or `None` if the
+/// iteration does not settle.
+fn solve(f: impl Fn(f64) -> f64, guess: f64) -> Option<f64> {
+    const MAX_ITERATIONS: usize = 100;
+    const TOLERANCE: f64 = 1e-10;
+    let mut x = guess;
+    for _ in 0..MAX_ITERATIONS {
+        let y = f(x);
+        let h = 1e-7 * x.abs().max(1.0);
+        let slope = (f(x + h) - y) / h;
+        if !slope.is_finite() || slope == 0.0 {
+            return None;
+        }
+        let next = x - y / slope;
+        // Rates at or below -100% have no meaning
+        if !next.is_finite() || next <= -1.0 {
+            return None;
         }
+        if (next - x).abs() < TOLERANCE {
+            return Some(next);
+        }
+        x = next;
     }
-    Some(sum)
+    None
 }
 
-/// Calculate the standard deviation (rounded) of values in the specified range.
-/// Returns `None` if any cell is in an error state, or `Some(0)` if fewer than 2 cells.
-pub fn stdev_range<F>(
-    start: (u16, u16),
-    end: (u16, u16),
-    get_val: F,
-) -> Option<i32>
+/// Evaluate PMT, PV, FV, NPER or RATE from their numeric arguments, using the
+/// usual sign convention: money paid out is negative, money received positive.
+/// Optional arguments default to 0 (and RATE's guess to 10%); `type` 1 means
+/// payments fall at the start of each period rather than the end.
+fn time_value(func: &str, x: &[f64]) -> Cell {
+    let arg = |i: usize| x.get(i).copied().unwrap_or(0.0);
+    // The future value of `pv` now plus `n` payments of `pmt`, at rate `r`
+    let future = |r: f64, n: f64, pmt: f64, pv: f64, due: f64| {
+        if r == 0.0 {
+            pv + pmt * n
+        } else {
+            let growth = (1.0 + r).powf(n);
+            pv * growth + pmt * (1.0 + r * due) * (growth - 1.0) / r
+        }
+    };
+    let due = if arg(4) != 0.0 { 1.0 } else { 0.0 };
+    let result = match func {
+        "PMT" => {
+            let (r, n, pv, fv) = (arg(0), arg(1), arg(2), arg(3));
+            if n == 0.0 {
+                return Cell::Err(CellError::Num);
+            }
+            // The payment that brings pv to fv: future(pmt) + fv = 0 is linear in pmt
+            let without = future(r, n, 0.0, pv, due) + fv;
+            let per_payment = future(r, n, 1.0, 0.0, due);
+            -without / per_payment
+        }
+        "PV" => {
+            let (r, n, pmt, fv) = (arg(0), arg(1), arg(2), arg(3));
+            -(future(r, n, pmt, 0.0, due) + fv) / (1.0 + r).powf(n)
+        }
+        "FV" => -future(arg(0), arg(1), arg(2), arg(3), due),
+        "NPER" => {
+            let (r, pmt, pv, fv) = (arg(0), arg(1), arg(2), arg(3));
+            if r == 0.0 {
+                if pmt == 0.0 {
+                    return Cell::Err(CellError::Num);
+                }
+                -(pv + fv) / pmt
+            } else {
+                let payment = pmt * (1.0 + r * due);
+                let ratio = (payment - fv * r) / (payment + pv * r);
+                if ratio <= 0.0 {
+                    return Cell::Err(CellError::Num);
+                }
+                ratio.ln() / (1.0 + r).ln()
+            }
+        }
+        _ => {
+            let (n, pmt, pv, fv) = (arg(0), arg(1), arg(2), arg(3));
+            let guess = x.get(5).copied().unwrap_or(0.1);
+            match solve(|r| future(r, n, pmt, pv, due) + fv, guess) {
+                Some(r) => r,
+                None => return Cell::Err(CellError::Num),
+            }
+        }
+    };
+    finite(result)
+}
+
+/// Evaluate COUNTIF, SUMIF or AVERAGEIF.  `target` is the optional range to
+/// sum or average, which must have the same shape as `range`; without it the
+/// matching cells of `range` itself are used.
+fn eval_conditional<F>(
+    func: &str,
+    range: &Expr,
+    criteria: &Expr,
+    target: Option<&Expr>,
+    get_val: &F,
+) -> Cell
 where
-    F: Fn((u16, u16)) -> Option<i32>,
+    F: Fn((u16, u16)) -> Option<Cell>,
 {
-    // First pass: sum and count
-    let mut sum: f64 = 0.0;
-    let mut count: usize = 0;
-    for c in start.0..=end.0 {
-        for r in start.1..=end.1 {
-            let v = get_val((c, r))? as f64;
-            sum += v;
+    let tested = match area(range) {
+        Some(a) => a,
+        None => return Cell::Err(CellError::Value),
+    };
+    let used = match target.map(area) {
+        None => tested,
+        Some(Some(a)) if shape(a) == shape(tested) => a,
+        Some(_) => return Cell::Err(CellError::Value),
+    };
+    let criterion = match Criterion::new(eval_expr(criteria, get_val)) {
+        Ok(c) => c,
+        Err(e) => return e,
+    };
+    let mut count = 0;
+    let mut matched = Vec::new();
+    for (cell, target) in cells_in(tested).zip(cells_in(used)) {
+        // Cells never set match no criterion
+        let Some(value) = get_val(cell) else {
+            continue;
+        };
+        if criterion.matches(&value) {
             count += 1;
+            match value_at(get_val, target) {
+                v if v.is_error() => return v,
+                v @ (Cell::Value(_) | Cell::Float(_)) => matched.push(v),
+                // Text and booleans are counted but not summed
+                _ => {}
+            }
         }
     }
-    if count <= 1 {
-        return Some(0);
+    match func {
+        "COUNTIF" => Cell::Value(count),
+        "SUMIF" => sum_values(&matched),
+        _ if matched.is_empty() => Cell::Err(CellError::DivZero),
+        _ => avg_values(&matched),
     }
-    let mean = sum / count as f64;
+}
+
+/// The contents of a cell, reading one that was never set as 0.
+fn value_at<F>(get_val: &F, cell: (u16, u16)) -> Cell
+where
+    F: Fn((u16, u16)) -> Option<Cell>,
+{
+    get_val(cell).unwrap_or_else(Cell::new)
+}
 
-    // Second pass: accumulate squared deviations
-    let mut var_sum: f64 = 0.0;
-    for c in start.0..=end.0 {
-        for r in start.1..=end.1 {
-            let v = get_val((c, r))? as f64;
-            let diff = v - mean;
-            var_sum += diff * diff;
+/// The values of a function's arguments, with each range expanded into the
+/// cells of it that have been set.  The flag is set for values that came from
+/// a range.
+fn arg_values<F>(args: &[Expr], get_val: &F) -> Vec<(Cell, bool)>
+where
+    F: Fn((u16, u16)) -> Option<Cell>,
+{
+    let mut values = Vec::new();
+    for arg in args {
+        if let Expr::Range(start, end) = arg {
+            values.extend(
+                cells_in((start.coord, end.coord)).filter_map(|c| Some((get_val(c)?, true))),
+            );
+        } else {
+            values.push((eval_expr(arg, get_val), false));
         }
     }
-    let variance = var_sum / count as f64;
-    Some(variance.sqrt().round() as i32)
+    values
 }
 
-/// Evaluate a range function (MIN/MAX/AVG/SUM/STDEV/SLEEP).
-/// The callback returns `Some(value)` for each cell, or `None` to signal an error.
-/// Returns `Some(aggregate)` or `None`.
-pub fn eval_range<F>(
-    func: &str,
-    start: (u16, u16),
-    end: (u16, u16),
-    get_val: F,
-) -> Option<i32>
+/// The numeric values among a function's arguments, for the aggregates.
+/// Text inside a range is skipped, text passed directly gives `#VALUE!` and
+/// booleans count as 1 or 0.  Returns the first error found.
+fn numbers(values: Vec<(Cell, bool)>) -> Result<Vec<Cell>, Cell> {
+    let mut nums = Vec::with_capacity(values.len());
+    for (v, from_range) in values {
+        match v {
+            Cell::Err(_) => return Err(v),
+            Cell::Text(_) if from_range => {}
+            Cell::Text(_) => return Err(Cell::Err(CellError::Value)),
+            Cell::Bool(b) => nums.push(Cell::Value(b as i32)),
+            v => nums.push(v),
+        }
+    }
+    Ok(nums)
+}
+
+/// Calculate the minimum of the values, or `Value(0)` if there are none.
+pub fn min_values(values: &[Cell]) -> Cell {
+    let mut min_val = Cell::Value(0);
+    let mut min = f64::INFINITY;
+    for v in values {
+        if let Some(x) = v.as_f64() {
+            if x < min {
+                min = x;
+                min_val = v.clone();
+            }
+        }
+    }
+    min_val
+}
+
+/// Calculate the maximum of the values, or `Value(0)` if there are none.
+pub fn max_values(values: &[Cell]) -> Cell {
+    let mut max_val = Cell::Value(0);
+    let mut max = f64::NEG_INFINITY;
+    for v in values {
+        if let Some(x) = v.as_f64() {
+            if x > max {
+                max = x;
+                max_val = v.clone();
+            }
+        }
+    }
+    max_val
+}
+
+/// Calculate the exact average of the values, or `Value(0)` if there are none.
+pub fn avg_values(values: &[Cell]) -> Cell {
+    if values.is_empty() {
+        return Cell::Value(0);
+    }
+    let sum: f64 = values.iter().filter_map(Cell::as_f64).sum();
+    finite(sum / values.len() as f64)
+}
+
+/// Calculate the sum of the values.
+/// The sum stays an integer unless a decimal value is included.
+pub fn sum_values(values: &[Cell]) -> Cell {
+    let mut sum = Cell::Value(0);
+    for v in values {
+        sum = eval_binary(BinOp::Add, &sum, v);
+        if sum.is_error() {
+            return sum;
+        }
+    }
+    sum
+}
+
+/// Calculate the population standard deviation of the values,
+/// or `Value(0)` if there are fewer than 2.
+pub fn stdev_values(values: &[Cell]) -> Cell {
+    if values.len() <= 1 {
+        return Cell::Value(0);
+    }
+    match variance(values, false) {
+        Ok(v) => finite(v.sqrt()),
+        Err(e) => e,
+    }
+}
+
+/// The sample (`sample` set) or population variance of the values.
+/// Gives `#DIV/0!` if there are too few values.
+fn variance(values: &[Cell], sample: bool) -> Result<f64, Cell> {
+    let nums: Vec<f64> = values.iter().filter_map(Cell::as_f64).collect();
+    let n = nums.len();
+    if n == 0 || (sample && n < 2) {
+        return Err(Cell::Err(CellError::DivZero));
+    }
+    let mean = nums.iter().sum::<f64>() / n as f64;
+    let var_sum: f64 = nums.iter().map(|x| (x - mean) * (x - mean)).sum();
+    Ok(var_sum / (n - sample as usize) as f64)
+}
+
+/// Calculate the middle value, or the mean of the two middle values.
+/// Gives `#NUM!` if there are no values.
+pub fn median_values(values: &[Cell]) -> Cell {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(compare);
+    let n = sorted.len();
+    match n {
+        0 => Cell::Err(CellError::Num),
+        _ if n % 2 == 1 => sorted[n / 2].clone(),
+        _ => {
+            let (a, b) = (sorted[n / 2 - 1].as_f64(), sorted[n / 2].as_f64());
+            finite((a.unwrap_or_default() + b.unwrap_or_default()) / 2.0)
+        }
+    }
+}
+
+/// Find the most frequent value; ties go to the one seen first.
+/// Gives `#N/A` if no value occurs more than once.
+pub fn mode_values(values: &[Cell]) -> Cell {
+    let mut best: Option<(&Cell, usize)> = None;
+    for v in values {
+        let count = values.iter().filter(|w| compare(v, w).is_eq()).count();
+        if count > 1 && best.is_none_or(|(_, c)| count > c) {
+            best = Some((v, count));
+        }
+    }
+    best.map_or(Cell::Err(CellError::NA), |(v, _)| v.clone())
+}
+
+/// Calculate the k-th percentile (0 <= k <= 1), interpolating between values.
+/// Gives `#NUM!` for an empty list or a `k` out of range.
+pub fn percentile_values(values: &[Cell], k: f64) -> Cell {
+    if values.is_empty() || !(0.0..=1.0).contains(&k) {
+        return Cell::Err(CellError::Num);
+    }
+    let mut nums: Vec<f64> = values.iter().filter_map(Cell::as_f64).collect();
+    nums.sort_by(f64::total_cmp);
+    let rank = k * (nums.len() - 1) as f64;
+    let lo = rank.floor() as usize;
+    let hi = rank.ceil() as usize;
+    finite(nums[lo] + (nums[hi] - nums[lo]) * (rank - lo as f64))
+}
+
+/// Calculate the Pearson correlation of paired values.  Pairs where either
+/// side is not a number are skipped and errors are passed through.
+/// Gives `#DIV/0!` with fewer than 2 pairs or when either side does not vary.
+pub fn correl_values<I>(pairs: I) -> Cell
 where
-    F: Fn((u16, u16)) -> Option<i32>,
+    I: Iterator<Item = (Cell, Cell)>,
 {
-    // Special handling for SLEEP function
-    if func.eq_ignore_ascii_case("SLEEP") {
-        let sec = get_val(start)?;
-        if sec > 0 {
-            thread::sleep(Duration::from_secs(sec as u64));
-        }
-        return Some(sec);
-    }
-
-    // Dispatch to the appropriate helper
-    match func.to_uppercase().as_str() {
-        "MIN" => min_range(start, end, &get_val),
-        "MAX" => max_range(start, end, &get_val),
-        "AVG" => avg_range(start, end, &get_val),
-        "SUM" => sum_range(start, end, &get_val),
-        "STDEV" => stdev_range(start, end, &get_val),
-        _ => None,
+    let mut points = Vec::new();
+    for (x, y) in pairs {
+        if x.is_error() {
+            return x;
+        }
+        if y.is_error() {
+            return y;
+        }
+        if let (Cell::Value(_) | Cell::Float(_), Cell::Value(_) | Cell::Float(_)) = (&x, &y) {
+            points.push((
+                x.as_f64().unwrap_or_default(),
+                y.as_f64().unwrap_or_default(),
+            ));
+        }
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut sxy, mut sxx, mut syy) = (0.0, 0.0, 0.0);
+    for (x, y) in &points {
+        sxy += (x - mean_x) * (y - mean_y);
+        sxx += (x - mean_x) * (x - mean_x);
+        syy += (y - mean_y) * (y - mean_y);
+    }
+    if points.len() < 2 || sxx == 0.0 || syy == 0.0 {
+        return Cell::Err(CellError::DivZero);
+    }
+    finite(sxy / (sxx * syy).sqrt())
+}
+
+/// Evaluate an aggregate function (MIN, MAX, AVG, SUM, the statistics) over
+/// the numeric values of its arguments.
+/// STDEV is the population standard deviation, like STDEVP; STDEV.S and VAR
+/// are the sample versions.
+pub fn eval_aggregate(func: &str, values: &[Cell]) -> Cell {
+    match func {
+        "MIN" => min_values(values),
+        "MAX" => max_values(values),
+        "AVG" => avg_values(values),
+        "SUM" => sum_values(values),
+        "STDEV" => stdev_values(values),
+        "STDEVP" | "STDEV.S" => match variance(values, func == "STDEV.S") {
+            Ok(v) => finite(v.sqrt()),
+            Err(e) => e,
+        },
+        "VAR" | "VARP" => match variance(values, func == "VAR") {
+            Ok(v) => finite(v),
+            Err(e) => e,
+        },
+        "MEDIAN" => median_values(values),
+        "MODE" => mode_values(values),
+        _ => Cell::Err(CellError::Value),
     }
 }
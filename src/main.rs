@@ -1,6 +1,7 @@
 mod commands;
 mod function;
 mod parser;
+mod range_index;
 mod spreadsheet;
 
 use commands::handle_commands;
@@ -15,7 +16,7 @@ fn main() {
     }
     let rows: usize = args[1].parse().unwrap_or(0);
     let cols: usize = args[2].parse().unwrap_or(0);
-    if rows < 1 || rows > 999 || cols < 1 || cols > 18278 {
+    if !(1..=999).contains(&rows) || !(1..=18278).contains(&cols) {
         eprintln!(
             "Error: Invalid rows or cols; got {}x{}. Valid: 1≤rows≤999, 1≤cols≤18278.",
             rows, cols
@@ -1,3 +1,9 @@
+use crate::function::{is_volatile, signature};
+use crate::spreadsheet::CellError;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use regex::Regex;
+use std::collections::HashSet;
+
 pub struct Parser;
 
 impl Parser {
@@ -29,40 +35,591 @@ impl Parser {
         Some((col, row))
     }
 
-    /// Parses B1+C2 or 2+3 or B1+2 or 2+B1 into (op_code, lhs_str, rhs_str).
-    /// op_code: 1='+', 2='-', 3='*', 5='/'.
-    pub fn split_binary(expr: &str) -> Option<(char, &str, &str)> {
-        // Try each operator in order of precedence or as needed.
-        for op in ['+', '-', '*', '/'] {
-            if let Some(idx) = expr.find(op) {
-                let (lhs, rest) = expr.split_at(idx);
-                let rhs = &rest[1..];
-                if !lhs.trim().is_empty() && !rhs.trim().is_empty() {
-                    return Some((op, lhs, rhs));
+    /// Parses a cell reference as written in a formula, where a `$` before the
+    /// column letters and/or the row number marks that part as absolute:
+    /// `A1`, `$A$1`, `A$1` or `$A1`.
+    pub fn parse_cell_ref(s: &str) -> Option<CellRef> {
+        let abs_col = s.starts_with('$');
+        let rest = s.strip_prefix('$').unwrap_or(s);
+        let split = rest.find(|c: char| !c.is_ascii_alphabetic())?;
+        let (letters, numbers) = rest.split_at(split);
+        let abs_row = numbers.starts_with('$');
+        let numbers = numbers.strip_prefix('$').unwrap_or(numbers);
+        if !numbers.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let coord = Parser::cell_name_to_coord(&format!("{}{}", letters, numbers))?;
+        Some(CellRef {
+            coord,
+            abs_col,
+            abs_row,
+        })
+    }
+
+    /// Parses the two ends of a range: two cells (`A1:B3`), two columns (`A:B`,
+    /// every row) or two rows (`3:5`, every column).  Whole columns and rows
+    /// extend to `WHOLE` until `Expr::fit_to_sheet` trims them to the sheet.
+    fn parse_range_ends(a: &str, b: &str) -> Option<(CellRef, CellRef)> {
+        if let (Some(start), Some(end)) = (Parser::parse_cell_ref(a), Parser::parse_cell_ref(b)) {
+            return Some((start, end));
+        }
+        let whole = |coord, abs_col, abs_row| CellRef {
+            coord,
+            abs_col,
+            abs_row,
+        };
+        if let (Some((first, abs_first)), Some((last, abs_last))) = (column(a), column(b)) {
+            return Some((
+                whole((first, 1), abs_first, true),
+                whole((last, WHOLE), abs_last, true),
+            ));
+        }
+        let ((first, abs_first), (last, abs_last)) = (row(a)?, row(b)?);
+        Some((
+            whole((1, first), true, abs_first),
+            whole((WHOLE, last), true, abs_last),
+        ))
+    }
+
+    /// Parses a formula such as `(A1+B1)*2`, `-5+A1` or `SUM(A1:A10)/COUNT(A1:A10)`
+    /// into an `Expr`, honouring precedence, left associativity and parentheses.
+    /// Returns `None` if the text is not a well-formed formula, names an unknown
+    /// function or passes a function the wrong number or kind of arguments.
+    pub fn parse_formula(expr: &str) -> Option<Expr> {
+        let mut cursor = Cursor {
+            tokens: tokenize(expr)?,
+            pos: 0,
+        };
+        let ast = cursor.expr()?;
+        if cursor.pos == cursor.tokens.len() {
+            Some(ast)
+        } else {
+            None
+        }
+    }
+}
+
+/// Top-left and bottom-right corners of a rectangular range, as (col, row).
+pub type RangeRef = ((u16, u16), (u16, u16));
+
+/// Stands for the last row or column of the sheet at the far end of a whole
+/// column (`A:A`) or whole row (`3:3`) range.
+pub const WHOLE: u16 = u16::MAX;
+
+/// A column on its own, such as `B` or `$B` in `B:B`, with its `$` flag.
+fn column(s: &str) -> Option<(u16, bool)> {
+    let letters = s.strip_prefix('$').unwrap_or(s);
+    if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let (col, _) = Parser::cell_name_to_coord(&format!("{}1", letters))?;
+    Some((col, s.starts_with('$')))
+}
+
+/// A row on its own, such as `3` or `$3` in `3:3`, with its `$` flag.
+fn row(s: &str) -> Option<(u16, bool)> {
+    let digits = s.strip_prefix('$').unwrap_or(s);
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let row = digits.parse::<u16>().ok().filter(|&r| r >= 1)?;
+    Some((row, s.starts_with('$')))
+}
+
+/// A cell reference inside a formula.  The flags record which parts were
+/// written with a `$` and must stay fixed when the formula is copied or moved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellRef {
+    /// (col, row), as returned by `Parser::cell_name_to_coord`.
+    pub coord: (u16, u16),
+    pub abs_col: bool,
+    pub abs_row: bool,
+}
+
+/// Binary operators understood by the formula grammar.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// Exponentiation, `^`.
+    Pow,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Text concatenation, `&`.
+    Concat,
+}
+
+/// A regular expression compiled along with its formula.
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// A parsed formula.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i32),
+    Float(f64),
+    Text(String),
+    Bool(bool),
+    /// A date literal such as `#2024-01-15#` or `#2024-01-15T09:30#`.
+    Date(NaiveDateTime),
+    /// An error literal such as `#DIV/0!`.
+    Error(CellError),
+    Ref(CellRef),
+    /// Top-left and bottom-right corners; only valid as a function argument.
+    Range(CellRef, CellRef),
+    /// The literal pattern of a REGEX function, compiled once at parse time.
+    Pattern(Pattern),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    /// Collect every single cell and every range this expression reads.
+    pub fn dependencies(&self, cells: &mut HashSet<(u16, u16)>, ranges: &mut Vec<RangeRef>) {
+        match self {
+            Expr::Number(_)
+            | Expr::Float(_)
+            | Expr::Text(_)
+            | Expr::Bool(_)
+            | Expr::Date(_)
+            | Expr::Error(_)
+            | Expr::Pattern(_) => {}
+            Expr::Ref(r) => {
+                cells.insert(r.coord);
+            }
+            Expr::Range(start, end) => ranges.push((start.coord, end.coord)),
+            Expr::Neg(inner) => inner.dependencies(cells, ranges),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.dependencies(cells, ranges);
+                rhs.dependencies(cells, ranges);
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    arg.dependencies(cells, ranges);
+                }
+            }
+        }
+    }
+
+    /// Trim whole-column and whole-row ranges to a sheet of `cols` by `rows`.
+    pub fn fit_to_sheet(&mut self, cols: u16, rows: u16) {
+        match self {
+            Expr::Range(_, end) => {
+                if end.coord.0 == WHOLE {
+                    end.coord.0 = cols;
+                }
+                if end.coord.1 == WHOLE {
+                    end.coord.1 = rows;
                 }
             }
+            Expr::Neg(inner) => inner.fit_to_sheet(cols, rows),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.fit_to_sheet(cols, rows);
+                rhs.fit_to_sheet(cols, rows);
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    arg.fit_to_sheet(cols, rows);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the expression calls a volatile function such as `RAND()`.
+    pub fn is_volatile(&self) -> bool {
+        match self {
+            Expr::Neg(inner) => inner.is_volatile(),
+            Expr::Binary(_, lhs, rhs) => lhs.is_volatile() || rhs.is_volatile(),
+            Expr::Call(func, args) => is_volatile(func) || args.iter().any(Expr::is_volatile),
+            _ => false,
         }
-        None
     }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(String),
+    Name(String),
+    Text(String),
+    Date(NaiveDateTime),
+    Error(CellError),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Colon,
+    Comma,
+}
 
-    /// Parses MAX(A1:B3), etc., returning (func, start, end).
-    pub fn parse_range(expr: &str) -> Option<(&str, (u16, u16), (u16, u16))> {
-        let expr = expr.trim();
-        for &func in &["MIN", "MAX", "AVG", "SUM", "STDEV", "SLEEP"] {
-            let open = format!("{}(", func);
-            if expr.starts_with(&open) && expr.ends_with(')') {
-                let inside = &expr[open.len()..expr.len() - 1];
-                if let Some(colon) = inside.find(':') {
-                    let a = inside[..colon].trim();
-                    let b = inside[colon + 1..].trim();
-                    if let (Some(s), Some(e)) =
-                        (Parser::cell_name_to_coord(a), Parser::cell_name_to_coord(b))
-                    {
-                        return Some((func, s, e));
+/// Split a formula into tokens.  Returns `None` on any character the grammar
+/// does not know about.
+fn tokenize(s: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit()
+            || (c == '.' && i + 1 < chars.len() && chars[i + 1].is_ascii_digit())
+        {
+            // Digits, an optional fraction and an optional exponent: 42, 3.75, 1e3, .5
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            if i < chars.len() && chars[i] == '.' {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Number(chars[start..i].iter().collect()));
+        } else if c.is_ascii_alphabetic() || c == '$' {
+            // Cell names, possibly with `$` markers, and function names; the
+            // latter may contain dots (ERROR.TYPE)
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric() || chars[i] == '.' || chars[i] == '$')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Name(chars[start..i].iter().collect()));
+        } else if c == '"' {
+            // String literal; a doubled quote stands for one quote character
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    Some('"') if chars.get(i + 1) == Some(&'"') => {
+                        text.push('"');
+                        i += 2;
+                    }
+                    Some('"') => break,
+                    Some(&ch) => {
+                        text.push(ch);
+                        i += 1;
                     }
+                    None => return None,
+                }
+            }
+            i += 1;
+            tokens.push(Token::Text(text));
+        } else if c == '#' {
+            // Date literals between `#` marks, so that 2024-01-15 stays a subtraction
+            if let Some((date, len)) = date_literal(&chars[i + 1..]) {
+                if chars.get(i + 1 + len) == Some(&'#') {
+                    tokens.push(Token::Date(date));
+                    i += len + 2;
+                    continue;
                 }
             }
+            // Error literals: #DIV/0!, #N/A, ...
+            let rest: String = chars[i..].iter().collect::<String>().to_ascii_uppercase();
+            let err = CellError::ALL
+                .into_iter()
+                .find(|e| rest.starts_with(e.code()))?;
+            i += err.code().len();
+            tokens.push(Token::Error(err));
+        } else {
+            tokens.push(match c {
+                '+' => Token::Op("+"),
+                '-' => Token::Op("-"),
+                '*' => Token::Op("*"),
+                '/' => Token::Op("/"),
+                '^' => Token::Op("^"),
+                '&' => Token::Op("&"),
+                '=' => Token::Op("="),
+                '<' => match chars.get(i + 1) {
+                    Some('=') => {
+                        i += 1;
+                        Token::Op("<=")
+                    }
+                    Some('>') => {
+                        i += 1;
+                        Token::Op("<>")
+                    }
+                    _ => Token::Op("<"),
+                },
+                '>' => match chars.get(i + 1) {
+                    Some('=') => {
+                        i += 1;
+                        Token::Op(">=")
+                    }
+                    _ => Token::Op(">"),
+                },
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                ':' => Token::Colon,
+                ',' => Token::Comma,
+                _ => return None,
+            });
+            i += 1;
+        }
+    }
+    Some(tokens)
+}
+
+/// Recognise an ISO date at the start of `chars`, with an optional time of day
+/// (`2024-01-15`, `2024-01-15T09:30` or `2024-01-15T09:30:00`); in a formula
+/// it is written between `#` marks, as in `#2024-01-15#`.  Returns the
+/// date and the number of characters it takes up.
+fn date_literal(chars: &[char]) -> Option<(NaiveDateTime, usize)> {
+    if chars.len() < 10 || !chars[..4].iter().all(char::is_ascii_digit) || chars[4] != '-' {
+        return None;
+    }
+    let text = |len: usize| chars[..len].iter().collect::<String>();
+    let ends_at = |len: usize| !chars.get(len).is_some_and(|c| c.is_ascii_alphanumeric());
+    for (len, format) in [(19, "%Y-%m-%dT%H:%M:%S"), (16, "%Y-%m-%dT%H:%M")] {
+        if chars.len() >= len && ends_at(len) {
+            if let Ok(date) = NaiveDateTime::parse_from_str(&text(len), format) {
+                return Some((date, len));
+            }
+        }
+    }
+    let date = NaiveDate::parse_from_str(&text(10), "%Y-%m-%d").ok()?;
+    ends_at(10).then_some((date.and_time(NaiveTime::MIN), 10))
+}
+
+/// Recursive-descent parser over a token list.
+///
+/// ```text
+/// expr   := concat (('=' | '<>' | '<' | '<=' | '>' | '>=') concat)*
+/// concat := sum ('&' sum)*
+/// sum    := term (('+' | '-') term)*
+/// term   := unary (('*' | '/') unary)*
+/// unary  := ('-' | '+') unary | power
+/// power  := atom ['^' unary]
+/// atom   := number | string | date | TRUE | FALSE | error | cell | call | '(' expr ')'
+/// call   := name '(' [arg (',' arg)*] ')'
+/// arg    := cell ':' cell | column ':' column | row ':' row | expr
+/// ```
+struct Cursor {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Cursor {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn expr(&mut self) -> Option<Expr> {
+        let mut lhs = self.concat()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("=")) => BinOp::Eq,
+                Some(Token::Op("<>")) => BinOp::Ne,
+                Some(Token::Op("<")) => BinOp::Lt,
+                Some(Token::Op("<=")) => BinOp::Le,
+                Some(Token::Op(">")) => BinOp::Gt,
+                Some(Token::Op(">=")) => BinOp::Ge,
+                _ => return Some(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.concat()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn concat(&mut self) -> Option<Expr> {
+        let mut lhs = self.sum()?;
+        while self.peek() == Some(&Token::Op("&")) {
+            self.pos += 1;
+            let rhs = self.sum()?;
+            lhs = Expr::Binary(BinOp::Concat, Box::new(lhs), Box::new(rhs));
+        }
+        Some(lhs)
+    }
+
+    fn sum(&mut self) -> Option<Expr> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("+")) => BinOp::Add,
+                Some(Token::Op("-")) => BinOp::Sub,
+                _ => return Some(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.term()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn term(&mut self) -> Option<Expr> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("*")) => BinOp::Mul,
+                Some(Token::Op("/")) => BinOp::Div,
+                _ => return Some(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Option<Expr> {
+        match self.peek() {
+            Some(Token::Op("-")) => {
+                self.pos += 1;
+                // Fold the sign into a literal so that i32::MIN stays representable,
+                // unless the literal is raised to a power: -2^2 is -(2^2).
+                if let Some(Token::Number(digits)) = self.peek().cloned() {
+                    if self.tokens.get(self.pos + 1) != Some(&Token::Op("^")) {
+                        self.pos += 1;
+                        return number(&format!("-{}", digits));
+                    }
+                }
+                Some(Expr::Neg(Box::new(self.unary()?)))
+            }
+            Some(Token::Op("+")) => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    /// `^` is right-associative and binds tighter than a sign, so `2^3^2` is
+    /// `2^(3^2)` and `-2^2` is `-(2^2)`; the exponent may carry its own sign.
+    fn power(&mut self) -> Option<Expr> {
+        let base = self.atom()?;
+        if self.peek() == Some(&Token::Op("^")) {
+            self.pos += 1;
+            let exponent = self.unary()?;
+            return Some(Expr::Binary(BinOp::Pow, Box::new(base), Box::new(exponent)));
+        }
+        Some(base)
+    }
+
+    fn atom(&mut self) -> Option<Expr> {
+        match self.next()? {
+            Token::Number(digits) => number(&digits),
+            Token::Text(text) => Some(Expr::Text(text)),
+            Token::Date(date) => Some(Expr::Date(date)),
+            Token::Error(e) => Some(Expr::Error(e)),
+            Token::Name(name) => {
+                if self.peek() == Some(&Token::LParen) {
+                    self.pos += 1;
+                    self.call(name)
+                } else if name.eq_ignore_ascii_case("TRUE") {
+                    Some(Expr::Bool(true))
+                } else if name.eq_ignore_ascii_case("FALSE") {
+                    Some(Expr::Bool(false))
+                } else {
+                    Parser::parse_cell_ref(&name).map(Expr::Ref)
+                }
+            }
+            Token::LParen => {
+                let inner = self.expr()?;
+                match self.next()? {
+                    Token::RParen => Some(inner),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse the argument list of `name(`, checking it against the function's
+    /// signature.
+    fn call(&mut self, name: String) -> Option<Expr> {
+        let sig = signature(&name)?;
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RParen) {
+            self.pos += 1;
+        } else {
+            loop {
+                args.push(self.arg()?);
+                match self.next()? {
+                    Token::Comma => {}
+                    Token::RParen => break,
+                    _ => return None,
+                }
+            }
+        }
+        if args.len() < sig.min_args || args.len() > sig.max_args {
+            return None;
+        }
+        if !sig.ranges && args.iter().any(|a| matches!(a, Expr::Range(..))) {
+            return None;
+        }
+        // Compile a literal pattern now rather than each time the cell is evaluated;
+        // an invalid one is left as text to give #VALUE! then
+        if name.starts_with("REGEX") {
+            if let Expr::Text(pattern) = &args[1] {
+                if let Ok(re) = Regex::new(pattern) {
+                    args[1] = Expr::Pattern(Pattern(re));
+                }
+            }
+        }
+        Some(Expr::Call(name, args))
+    }
+
+    fn arg(&mut self) -> Option<Expr> {
+        if let (Some(a), Some(Token::Colon), Some(b)) = (
+            self.tokens.get(self.pos),
+            self.tokens.get(self.pos + 1),
+            self.tokens.get(self.pos + 2),
+        ) {
+            // Rows on their own (3:5) are read as numbers
+            let (Token::Name(a) | Token::Number(a), Token::Name(b) | Token::Number(b)) = (a, b)
+            else {
+                return None;
+            };
+            let (start, end) = Parser::parse_range_ends(a, b)?;
+            self.pos += 3;
+            return Some(Expr::Range(start, end));
+        }
+        self.expr()
+    }
+}
+
+/// Turn a numeric literal into an integer when it is written as one and fits,
+/// otherwise into a decimal.  A literal too large even for a decimal, such as
+/// `1e999`, is rejected.
+fn number(text: &str) -> Option<Expr> {
+    if !text.contains(['.', 'e', 'E']) {
+        if let Ok(v) = text.parse::<i32>() {
+            return Some(Expr::Number(v));
         }
-        None
     }
+    text.parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .map(Expr::Float)
 }
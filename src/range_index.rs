@@ -0,0 +1,63 @@
+use crate::parser::RangeRef;
+use std::collections::HashMap;
+
+/// Side of the square blocks of cells the index is divided into.
+const CHUNK: u16 = 64;
+
+/// A range and the cell of the formula that reads it.
+type Entry = ((u16, u16), RangeRef);
+
+/// Answers "which range formulas read cell X" without scanning every range.
+///
+/// The sheet is divided into `CHUNK`×`CHUNK` blocks, and each range is listed
+/// under every block it overlaps.  A lookup then only tests the ranges listed
+/// under the cell's own block.  A whole column of a 999-row sheet touches 16
+/// blocks and a whole row of 18278 columns 286.
+#[derive(Default)]
+pub struct RangeIndex {
+    chunks: HashMap<(u16, u16), Vec<Entry>>,
+}
+
+impl RangeIndex {
+    /// Record that the formula at `formula` reads each of `ranges`.
+    pub fn insert(&mut self, formula: (u16, u16), ranges: &[RangeRef]) {
+        for &range in ranges {
+            for chunk in chunks_of(range) {
+                self.chunks.entry(chunk).or_default().push((formula, range));
+            }
+        }
+    }
+
+    /// Forget the ranges `insert` recorded for `formula`.
+    pub fn remove(&mut self, formula: (u16, u16), ranges: &[RangeRef]) {
+        for &range in ranges {
+            for chunk in chunks_of(range) {
+                if let Some(entries) = self.chunks.get_mut(&chunk) {
+                    entries.retain(|&(f, r)| f != formula || r != range);
+                    if entries.is_empty() {
+                        self.chunks.remove(&chunk);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The formulas with a range covering `cell`.  A formula with several such
+    /// ranges is listed once for each.
+    pub fn covering(&self, cell: (u16, u16)) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.chunks
+            .get(&(cell.0 / CHUNK, cell.1 / CHUNK))
+            .into_iter()
+            .flatten()
+            .filter(move |(_, (start, end))| {
+                (start.0..=end.0).contains(&cell.0) && (start.1..=end.1).contains(&cell.1)
+            })
+            .map(|&(formula, _)| formula)
+    }
+}
+
+/// The blocks a range overlaps.
+fn chunks_of((start, end): RangeRef) -> impl Iterator<Item = (u16, u16)> {
+    (start.0 / CHUNK..=end.0 / CHUNK)
+        .flat_map(move |c| (start.1 / CHUNK..=end.1 / CHUNK).map(move |r| (c, r)))
+}
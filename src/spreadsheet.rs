@@ -1,19 +1,141 @@
-use crate::function::{eval_binary, eval_range};
-use crate::parser::Parser;
+use crate::function::{eval_expr, with_seed};
+use crate::parser::{Expr, Parser, RangeRef};
+use crate::range_index::RangeIndex;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
+use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
-use std::thread;
-use std::time::Duration;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Cell {
     Value(i32),
-    Err,
+    Float(f64),
+    Text(String),
+    Bool(bool),
+    /// A date, or a date and time of day.
+    Date(NaiveDateTime),
+    Err(CellError),
+}
+
+/// Why a cell has no value.  The kind is kept as the error spreads to dependents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellError {
+    /// Division by zero.
+    DivZero,
+    /// A reference outside the sheet.
+    Ref,
+    /// An operand of the wrong kind, such as a bare range.
+    Value,
+    /// A cell caught in a circular dependency.
+    Cycle,
+    /// A numeric argument outside a function's domain, or an iterative
+    /// calculation such as IRR that did not converge.
+    Num,
+    /// A result too large to represent.
+    Overflow,
+    /// No value is available.
+    NA,
+}
+
+impl CellError {
+    pub const ALL: [CellError; 7] = [
+        CellError::DivZero,
+        CellError::Ref,
+        CellError::Value,
+        CellError::Cycle,
+        CellError::Num,
+        CellError::Overflow,
+        CellError::NA,
+    ];
+
+    /// The code shown in the sheet, which is also how formulas spell the error.
+    pub fn code(self) -> &'static str {
+        match self {
+            CellError::DivZero => "#DIV/0!",
+            CellError::Ref => "#REF!",
+            CellError::Value => "#VALUE!",
+            CellError::Cycle => "#CYCLE!",
+            CellError::Num => "#NUM!",
+            CellError::Overflow => "#OVF!",
+            CellError::NA => "#N/A",
+        }
+    }
+
+    /// The number `ERROR.TYPE` reports, following the usual spreadsheet numbering
+    /// for the standard kinds.
+    pub fn type_number(self) -> i32 {
+        match self {
+            CellError::DivZero => 2,
+            CellError::Value => 3,
+            CellError::Ref => 4,
+            CellError::Num => 6,
+            CellError::NA => 7,
+            CellError::Cycle => 8,
+            CellError::Overflow => 9,
+        }
+    }
 }
 
 impl Cell {
     pub fn new() -> Self {
         Cell::Value(0)
     }
+
+    /// The numeric value of the cell (booleans count as 1 and 0, dates as their
+    /// serial day number), or `None` for text or an error.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Cell::Value(v) => Some(*v as f64),
+            Cell::Float(v) => Some(*v),
+            Cell::Bool(b) => Some(*b as i32 as f64),
+            Cell::Date(d) => Some(date_serial(*d)),
+            Cell::Text(_) | Cell::Err(_) => None,
+        }
+    }
+
+    /// The value of an integer or boolean cell, or `None` for anything else.
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            Cell::Value(v) => Some(*v),
+            Cell::Bool(b) => Some(*b as i32),
+            _ => None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Cell::Err(_))
+    }
+}
+
+/// Day 0 of the serial day numbers, as used by other spreadsheets: 1 is 1900-01-01
+/// on their calendar, and every date from 1900-03-01 on has the same number.
+fn date_epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(1899, 12, 30)
+        .unwrap_or_default()
+        .and_time(NaiveTime::MIN)
+}
+
+/// The serial day number of a date; the time of day is the fraction.
+pub fn date_serial(d: NaiveDateTime) -> f64 {
+    (d - date_epoch()).num_seconds() as f64 / 86400.0
+}
+
+/// The date for a serial day number, to the nearest second, or `None` if it is
+/// out of range.
+pub fn serial_date(serial: f64) -> Option<NaiveDateTime> {
+    let secs = (serial * 86400.0).round();
+    if !secs.is_finite() || secs.abs() > i64::MAX as f64 / 2.0 {
+        return None;
+    }
+    date_epoch().checked_add_signed(TimeDelta::try_seconds(secs as i64)?)
+}
+
+/// Format a date as ISO 8601, leaving out the time at midnight.
+pub fn format_date(d: NaiveDateTime) -> String {
+    if d.time() == NaiveTime::MIN {
+        d.format("%Y-%m-%d").to_string()
+    } else {
+        d.format("%Y-%m-%dT%H:%M:%S").to_string()
+    }
 }
 
 /// Convert a 1-based column index into letters (1→"A", 27→"AA")
@@ -27,59 +149,152 @@ fn col_to_letter(mut n: usize) -> String {
     s.chars().rev().collect()
 }
 
+/// The name of a cell as (col, row), such as "B3".
+pub fn cell_name((col, row): (u16, u16)) -> String {
+    format!("{}{}", col_to_letter(col as usize), row)
+}
+
+/// Fewest cells in one level of a recalculation for it to be shared between threads.
+const PARALLEL_MIN: usize = 64;
+
+/// Parsed formula and the single cells it references.  Every formula cell has
+/// one, so recalculation evaluates the stored tree without parsing again.
+type NormalDeps = (Expr, HashSet<(u16, u16)>);
+/// Corners of every range a formula reads.
+type RangeDeps = Vec<RangeRef>;
+
+/// Format a decimal to fit in `width` characters where possible: whole numbers
+/// print without a fraction, others with as many digits as fit (trailing zeros
+/// trimmed), and values too large or too small for that in scientific notation,
+/// with fewer mantissa digits when needed to stay within `width`.
+fn format_float(v: f64, width: usize) -> String {
+    if v == 0.0 {
+        return "0".to_string();
+    }
+    if v.fract() == 0.0 && v.abs() < 1e15 {
+        return format!("{:.0}", v);
+    }
+    for decimals in (0..=6).rev() {
+        let mut s = format!("{:.*}", decimals, v);
+        if s.contains('.') {
+            s = s.trim_end_matches('0').trim_end_matches('.').to_string();
+        }
+        if s.len() <= width && s.parse::<f64>().is_ok_and(|r| r != 0.0) {
+            return s;
+        }
+    }
+    // Drop mantissa digits until it fits; with none left it always does
+    for decimals in (1..=2).rev() {
+        let s = format!("{:.*e}", decimals, v);
+        if s.len() <= width {
+            return s;
+        }
+    }
+    format!("{:.0e}", v)
+}
+
 pub struct Spreadsheet {
     pub rows: usize,
     pub cols: usize,
     pub parents_normal: HashMap<(u16, u16), HashSet<(u16, u16)>>,
-    pub child_normal: HashMap<(u16, u16), (String, HashSet<(u16, u16)>)>,
-    pub child_range: HashMap<(u16, u16), (String, (u16, u16), (u16, u16))>,
-    pub cells: Vec<Vec<Cell>>,
+    pub child_normal: HashMap<(u16, u16), NormalDeps>,
+    pub child_range: HashMap<(u16, u16), RangeDeps>,
+    /// The ranges of `child_range`, indexed by the cells they cover.
+    range_index: RangeIndex,
+    /// Cells whose formula calls RAND, NOW or another volatile function.
+    pub volatile: HashSet<(u16, u16)>,
+    /// Contents of every cell that has been set, keyed by (col, row).  Storage grows
+    /// with the cells in use rather than with the size of the sheet.
+    cells: HashMap<(u16, u16), Cell>,
+    /// Seed for RAND and RANDBETWEEN; set with the `seed` command.
+    seed: u64,
+    /// Number of edits so far, so that volatile cells draw new numbers each time.
+    generation: u64,
+    /// The loop found when `set_cell` last refused a formula as circular, from
+    /// the edited cell through the cells each one reads back to itself.
+    pub last_cycle: Vec<(u16, u16)>,
+    /// Whether recalculation may evaluate independent cells on several threads.
+    /// Results are the same either way; `disable_parallel` turns it off for debugging.
+    pub parallel: bool,
 }
 
 impl Spreadsheet {
     pub fn new(rows: usize, cols: usize) -> Self {
-        let mut cells = Vec::with_capacity(rows + 1);
-        for _ in 0..=rows {
-            cells.push(vec![Cell::new(); cols + 1]);
-        }
         Spreadsheet {
             rows,
             cols,
             parents_normal: HashMap::new(),
             child_normal: HashMap::new(),
             child_range: HashMap::new(),
-            cells,
+            range_index: RangeIndex::default(),
+            volatile: HashSet::new(),
+            cells: HashMap::new(),
+            seed: rand::random(),
+            generation: 0,
+            last_cycle: Vec::new(),
+            parallel: true,
         }
     }
 
-    /// Return `Some(v)` if cell is a value, or `None` if it's `Err` or out of bounds.
-    fn get_val(&self, (c, r): (u16, u16)) -> Option<i32> {
+    /// Return the cell's contents, `None` if it was never set, or `#REF!` if it
+    /// is out of bounds.
+    fn get_val(&self, (c, r): (u16, u16)) -> Option<Cell> {
         if r as usize <= self.rows && c as usize <= self.cols {
-            match &self.cells[r as usize][c as usize] {
-                Cell::Value(v) => Some(*v),
-                Cell::Err => None,
-            }
+            self.cells.get(&(c, r)).cloned()
         } else {
-            None
+            Some(Cell::Err(CellError::Ref))
         }
     }
 
+    /// The contents of a cell inside the sheet; cells never set show as 0.
+    fn cell(&self, coord: (u16, u16)) -> &Cell {
+        static EMPTY: Cell = Cell::Value(0);
+        self.cells.get(&coord).unwrap_or(&EMPTY)
+    }
+
+    /// Store a cell's value.  A cell set to 0 keeps its entry, so ranges can
+    /// still tell it from one that was never set.
+    fn set_val(&mut self, coord: (u16, u16), value: Cell) {
+        self.cells.insert(coord, value);
+    }
+
+    /// Parse a formula, fitting any whole-column or whole-row ranges to the sheet.
+    fn parse(&self, formula: &str) -> Option<Expr> {
+        let mut ast = Parser::parse_formula(formula)?;
+        ast.fit_to_sheet(self.cols as u16, self.rows as u16);
+        Some(ast)
+    }
+
+    /// Evaluate the formula of the cell at `coord`.  Random numbers are drawn
+    /// from a generator seeded by the sheet's seed, the edit count and the cell,
+    /// so a given seed and sequence of edits always gives the same sheet.
+    fn eval_cell(&self, coord: (u16, u16), ast: &Expr) -> Cell {
+        let seed = self.seed
+            ^ self.generation.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            ^ ((coord.0 as u64) << 32 | coord.1 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+        with_seed(seed, || eval_expr(ast, &|c| self.get_val(c)))
+    }
+
+    /// Reseed RAND and RANDBETWEEN and recalculate every volatile cell.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.generation = 0;
+        self.recalculate(self.volatile.iter().copied().collect(), None);
+    }
+
     /// Set a cell’s formula or literal.  Abort (no change) on any parse error,
-    /// except when `/0` in a binary formula, which writes `Err`.
+    /// except when `/0` in a formula, which writes `#DIV/0!`.  Returns the status
+    /// message code, which is 6 when the cell's result or that of a cell depending
+    /// on it overflowed and 4 when
+    /// the formula would be circular, with the loop left in `last_cycle`.
     pub fn set_cell(&mut self, coord: (u16, u16), expr: &str) -> u8 {
         if coord.1 as usize > self.rows || coord.0 as usize > self.cols {
             return 1; // Invalid cell
         }
-        // Check for out-of-bounds coordinates
-        //print parents and child dependencies before setting cell
-        // println!("parents: {:?}", self.parents_normal);
-        // println!("child: {:?}", self.child_normal);
-        // println!("child_range: {:?}", self.child_range);
         // 1) clear old dependencies but save them first
-        let old_cell_value = self.cells[coord.1 as usize][coord.0 as usize].clone();
         let mut removed_from_parents = Vec::new();
         let old_child_normal = self.child_normal.remove(&coord);
-        let old_child_range = self.child_range.remove(&coord);
+        let old_child_range = self.take_ranges(coord);
 
         // Track which entries we're removing from parents_normal
         for (parent_coord, deps) in self.parents_normal.iter_mut() {
@@ -89,316 +304,141 @@ impl Spreadsheet {
             }
         }
 
+        // 2) Parse: "42", "C5", "A1+B1*2", "SUM(A1:A10)/2", "SLEEP(B1)" ...
         let expr = expr.trim();
-
-        // 1a) Check for SLEEP function with a constant value: "SLEEP(5)"
-        if expr.starts_with("SLEEP(") && expr.ends_with(")") {
-            let arg_str = &expr[6..expr.len() - 1];
-            // Check if the argument contains a colon, which indicates a range
-            if arg_str.contains(':') {
-                // Range is not allowed in SLEEP function
-                // Restore old child dependencies
-                if let Some(old_normal) = old_child_normal {
-                    self.child_normal.insert(coord, old_normal);
-                }
-                if let Some(old_range) = old_child_range {
-                    self.child_range.insert(coord, old_range);
-                }
-                // Restore parents
-                for (parent_coord, child_coord) in removed_from_parents {
-                    self.parents_normal
-                        .entry(parent_coord)
-                        .or_default()
-                        .insert(child_coord);
-                }
-                return 3; // unrecognized cmd - range not allowed in SLEEP
-            }
-            // Try to parse as a literal integer
-            if let Ok(sleep_time) = arg_str.parse::<i32>() {
-                // Direct sleep with constant
-                if sleep_time > 0 {
-                    thread::sleep(Duration::from_secs(sleep_time as u64));
-                }
-                self.cells[coord.1 as usize][coord.0 as usize] = Cell::Value(sleep_time);
-                self.recalc_dependents(coord);
-                return 0;
-            }
-            // Try to parse as a cell reference
-            else if let Some(ref_cell) = Parser::cell_name_to_coord(arg_str) {
-                // Add dependency tracking
-                self.parents_normal
-                    .entry(ref_cell)
-                    .or_default()
-                    .insert(coord);
-                let mut refs = HashSet::new();
-                refs.insert(ref_cell);
-                self.child_normal.insert(coord, (expr.to_string(), refs));
-
-                if self.has_cycle() {
-                    //reverse the parents normal and child normal changes done above
-                    self.parents_normal
-                        .entry(ref_cell)
-                        .or_default()
-                        .remove(&coord);
-                    self.child_normal.remove(&coord);
-                    //get the old values in these hashmaps
-                    for (parent_coord, child_coord) in removed_from_parents {
-                        self.parents_normal
-                            .entry(parent_coord)
-                            .or_default()
-                            .insert(child_coord);
-                    }
-                    // Restore old child dependencies
-                    if let Some(old_normal) = old_child_normal {
-                        self.child_normal.insert(coord, old_normal);
-                    }
-                    if let Some(old_range) = old_child_range {
-                        self.child_range.insert(coord, old_range);
-                    }
-                    return 4;
-                }
-                match self.get_val(ref_cell) {
-                    Some(sleep_time) => {
-                        // Sleep using the referenced cell's value
-                        if sleep_time > 0 {
-                            thread::sleep(Duration::from_secs(sleep_time as u64));
-                        }
-                        self.cells[coord.1 as usize][coord.0 as usize] = Cell::Value(sleep_time);
-                    }
-                    None => {
-                        self.cells[coord.1 as usize][coord.0 as usize] = Cell::Err;
-                    }
-                }
-                self.recalc_dependents(coord);
-                return 0;
+        let ast = match self.parse(expr) {
+            Some(ast) => ast,
+            None => {
+                // Anything else → abort with no change
+                self.restore_dependencies(
+                    coord,
+                    old_child_normal,
+                    old_child_range,
+                    removed_from_parents,
+                );
+                return 3; // unrecognized cmd
             }
+        };
+        let mut refs = HashSet::new();
+        let mut ranges = Vec::new();
+        ast.dependencies(&mut refs, &mut ranges);
+
+        // Every range must be ordered and inside the sheet
+        let cols = self.cols as u16;
+        let rows = self.rows as u16;
+        if ranges
+            .iter()
+            .any(|&(start, end)| start.0 > end.0 || start.1 > end.1 || end.0 > cols || end.1 > rows)
+        {
+            self.restore_dependencies(
+                coord,
+                old_child_normal,
+                old_child_range,
+                removed_from_parents,
+            );
+            return 3; // unrecognized cmd
         }
 
-        // 2a) Binary: "A1+2", "3/0", etc.
-        if let Some((op_char, lhs_s, rhs_s)) = Parser::split_binary(expr) {
-            let op_code = match op_char {
-                '+' => 1,
-                '-' => 2,
-                '*' => 3,
-                '/' => 5,
-                _ => return 3, //// unrecognized cmd  invalid operator
-            };
-
-            // Evaluate lhs
-            let a = if let Some(c) = Parser::cell_name_to_coord(lhs_s) {
-                match self.get_val(c) {
-                    Some(val) => Cell::Value(val),
-                    //for None return Err
-                    None => Cell::Err,
-                }
-            } else {
-                match lhs_s.parse::<i32>() {
-                    Ok(val) => Cell::Value(val),
-                    Err(_) => return 3,
-                }
-            };
+        // 3) Refuse a formula that would read its own result
+        if let Some(cycle) = self.find_cycle(coord, &refs, &ranges) {
+            self.last_cycle = cycle;
+            self.restore_dependencies(
+                coord,
+                old_child_normal,
+                old_child_range,
+                removed_from_parents,
+            );
+            return 4;
+        }
 
-            // Evaluate rhs
-            let b = if let Some(c) = Parser::cell_name_to_coord(rhs_s) {
-                match self.get_val(c) {
-                    Some(val) => Cell::Value(val),
-                    None => Cell::Err,
-                }
-            } else {
-                match rhs_s.parse::<i32>() {
-                    Ok(val) => Cell::Value(val),
-                    Err(_) => return 3,
-                }
-            };
+        // 4) No cycle, proceed with evaluation
+        let volatile = ast.is_volatile();
+        if volatile {
+            self.volatile.insert(coord);
+        } else {
+            self.volatile.remove(&coord);
+        }
+        self.generation += 1;
+        let value = self.eval_cell(coord, &ast);
 
-            let new_cell = if op_code == 5 && b == Cell::Value(0) {
-                Cell::Err
-            }
-            //else if a or b is Err
-            else if a == Cell::Err || b == Cell::Err {
-                Cell::Err
-            }
-            //else if both are values
-            else if let (Cell::Value(va), Cell::Value(vb)) = (a, b) {
-                if let Some(v) = eval_binary(op_code, va, vb) {
-                    Cell::Value(v)
-                } else {
-                    return 5; // division by zero
-                }
-            } else {
-                return 3;
-            };
-            let mut updated_parents = Vec::new();
-            // adding new dependencies
-            let mut refs = HashSet::new();
-            if let Some(c) = Parser::cell_name_to_coord(lhs_s) {
-                updated_parents.push((c, coord));
-                self.parents_normal.entry(c).or_default().insert(coord);
-                refs.insert(c);
-            }
-            if let Some(c) = Parser::cell_name_to_coord(rhs_s) {
-                updated_parents.push((c, coord));
+        // Record the new dependencies.  Plain literals have nothing to
+        // recalculate, so only formulas that read cells or are volatile are tracked.
+        if !refs.is_empty() || !ranges.is_empty() || volatile {
+            for &c in &refs {
                 self.parents_normal.entry(c).or_default().insert(coord);
-                refs.insert(c);
             }
-            self.child_normal.insert(coord, (expr.to_string(), refs));
-            // Check for cycles
-            if self.has_cycle() {
-                // Reverse the parents_normal and child_normal changes done above
-                for (parent, child) in updated_parents {
-                    self.parents_normal
-                        .entry(parent)
-                        .or_default()
-                        .remove(&child);
-                }
-                self.child_normal.remove(&coord);
-                // Restore old child dependencies
-                if let Some(old_normal) = old_child_normal {
-                    self.child_normal.insert(coord, old_normal);
-                }
-                if let Some(old_range) = old_child_range {
-                    self.child_range.insert(coord, old_range);
-                }
-                // Restore parents
-                for (parent_coord, child_coord) in removed_from_parents {
-                    self.parents_normal
-                        .entry(parent_coord)
-                        .or_default()
-                        .insert(child_coord);
-                }
-                // Keep the old cell value
-                self.cells[coord.1 as usize][coord.0 as usize] = old_cell_value;
-                return 4;
+            self.child_normal.insert(coord, (ast, refs));
+            if !ranges.is_empty() {
+                self.set_ranges(coord, ranges);
             }
-            self.cells[coord.1 as usize][coord.0 as usize] = new_cell;
-            self.recalc_dependents(coord);
-            return 0;
         }
 
-        // 2b) Range: "SUM(A1:B3)"
-        if let Some((func, start, end)) = Parser::parse_range(expr) {
-            // if start > end => return 3, and check in bounds
-            if start.0 > end.0
-                || start.1 > end.1
-                || start.0 > self.cols as u16
-                || start.1 > self.rows as u16
-                || end.0 > self.cols as u16
-                || end.1 > self.rows as u16
-            {
-                return 3; // unrecognized cmd
-            }
-            // Add the range dependency
-            self.child_range
-                .insert(coord, (expr.to_string(), start, end));
-
-            // Check for cycles that might be created by this range reference
-            if self.has_cycle() {
-                // Cycle detected - remove the range dependency we just added
-                self.child_range.remove(&coord);
-                // Restore old child dependencies
-                if let Some(old_normal) = old_child_normal {
-                    self.child_normal.insert(coord, old_normal);
-                }
-                if let Some(old_range) = old_child_range {
-                    self.child_range.insert(coord, old_range);
-                }
-                // Restore parents
-                for (parent_coord, child_coord) in removed_from_parents {
-                    self.parents_normal
-                        .entry(parent_coord)
-                        .or_default()
-                        .insert(child_coord);
-                }
-                // Keep the old cell value
-                self.cells[coord.1 as usize][coord.0 as usize] = old_cell_value;
-                return 4;
-            }
-            // No cycle, proceed with evaluation
-            if let Some(v) = eval_range(func, start, end, |c| self.get_val(c)) {
-                self.cells[coord.1 as usize][coord.0 as usize] = Cell::Value(v);
-            } else {
-                self.cells[coord.1 as usize][coord.0 as usize] = Cell::Err;
-            }
-            self.recalc_dependents(coord);
-            return 0;
+        let overflowed = value == Cell::Err(CellError::Overflow);
+        self.set_val(coord, value);
+        if self.recalc_dependents(coord) || overflowed {
+            6
+        } else {
+            0
         }
+    }
 
-        // 2c) Single reference: "C5"
-        if let Some(c) = Parser::cell_name_to_coord(expr) {
-            // Add dependency tracking
-            self.parents_normal.entry(c).or_default().insert(coord);
-            let mut refs = HashSet::new();
-            refs.insert(c);
-            self.child_normal.insert(coord, (expr.to_string(), refs));
-
-            // Check for cycles
-            if self.has_cycle() {
-                // Cycle detected - remove the dependency we just added
-                self.parents_normal.entry(c).or_default().remove(&coord);
-                self.child_normal.remove(&coord);
-                // Restore old child dependencies
-                if let Some(old_normal) = old_child_normal {
-                    self.child_normal.insert(coord, old_normal);
-                }
-                if let Some(old_range) = old_child_range {
-                    self.child_range.insert(coord, old_range);
-                }
-                // Restore parents
-                for (parent_coord, child_coord) in removed_from_parents {
-                    self.parents_normal
-                        .entry(parent_coord)
-                        .or_default()
-                        .insert(child_coord);
-                }
-                // Keep the old cell value
-                self.cells[coord.1 as usize][coord.0 as usize] = old_cell_value;
-                return 4;
-            }
-            // No cycle, proceed with evaluation
-            let v = self.get_val(c);
-            match v {
-                Some(val) => self.cells[coord.1 as usize][coord.0 as usize] = Cell::Value(val),
-                None => self.cells[coord.1 as usize][coord.0 as usize] = Cell::Err,
-            }
-            self.recalc_dependents(coord);
-            return 0;
-        }
+    /// Record the ranges the formula at `coord` reads, in `child_range` and the
+    /// range index.
+    fn set_ranges(&mut self, coord: (u16, u16), ranges: RangeDeps) {
+        self.range_index.insert(coord, &ranges);
+        self.child_range.insert(coord, ranges);
+    }
 
-        // 2d) Literal: "42"
-        if let Ok(v) = expr.parse::<i32>() {
-            self.cells[coord.1 as usize][coord.0 as usize] = Cell::Value(v);
-            self.recalc_dependents(coord);
-            return 0;
-        }
+    /// Remove and return the ranges the formula at `coord` reads.
+    fn take_ranges(&mut self, coord: (u16, u16)) -> Option<RangeDeps> {
+        let ranges = self.child_range.remove(&coord)?;
+        self.range_index.remove(coord, &ranges);
+        Some(ranges)
+    }
 
-        // 2e) Anything else → abort with no change
-        // RESTORE OLD CHILD DEPENDENCIES
+    /// Put back the dependency entries `set_cell` cleared for `coord` before it
+    /// decided to leave the cell unchanged.
+    fn restore_dependencies(
+        &mut self,
+        coord: (u16, u16),
+        old_child_normal: Option<NormalDeps>,
+        old_child_range: Option<RangeDeps>,
+        removed_from_parents: Vec<((u16, u16), (u16, u16))>,
+    ) {
         if let Some(old_normal) = old_child_normal {
             self.child_normal.insert(coord, old_normal);
         }
         if let Some(old_range) = old_child_range {
-            self.child_range.insert(coord, old_range);
+            self.set_ranges(coord, old_range);
         }
-        // Restore parents
         for (parent_coord, child_coord) in removed_from_parents {
             self.parents_normal
                 .entry(parent_coord)
                 .or_default()
                 .insert(child_coord);
         }
+    }
 
-        return 3; // unrecognized cmd
+    /// Recompute all dependents of `start`, which has just been set, along with
+    /// every volatile cell and its dependents.  Returns whether any of them
+    /// overflowed that had not before.
+    pub fn recalc_dependents(&mut self, start: (u16, u16)) -> bool {
+        let mut starts = vec![start];
+        starts.extend(self.volatile.iter().filter(|&&c| c != start));
+        self.recalculate(starts, Some(start))
     }
 
-    /// Recompute all dependents of `start`.  If division-by-zero occurs in a child,
-    /// that child becomes `Err`; any other error in recomputation leaves it untouched.
-    pub fn recalc_dependents(&mut self, start: (u16, u16)) {
+    /// Recompute the formulas of `starts` (apart from `skip`, whose value is
+    /// already current) and everything that depends on them.  Errors keep their
+    /// kind as they spread, and a cell found on a circular path becomes `#CYCLE!`.
+    /// Returns whether a cell that was not `#OVF!` before has become so.
+    fn recalculate(&mut self, starts: Vec<(u16, u16)>, skip: Option<(u16, u16)>) -> bool {
         // Keep track of all cells that need to be recalculated
         let mut all_cells_to_update = Vec::new();
         let mut visited = HashSet::new();
 
         // Collect all cells affected by the change, including indirect dependencies
-        let mut queue = vec![start];
+        let mut queue = starts;
         while let Some(cell) = queue.pop() {
             if !visited.insert(cell) {
                 continue; // Skip if already visited
@@ -417,9 +457,8 @@ impl Spreadsheet {
             }
 
             // Check for range dependencies
-            for (&range_cell, (_, range_start, range_end)) in &self.child_range {
-                if is_within_range(cell, *range_start, *range_end) && !visited.contains(&range_cell)
-                {
+            for range_cell in self.range_index.covering(cell) {
+                if !visited.contains(&range_cell) {
                     queue.push(range_cell);
                 }
             }
@@ -428,15 +467,17 @@ impl Spreadsheet {
         // Now sort these cells topologically for correct calculation order
         let mut visited = HashSet::new();
         let mut visiting = HashSet::new();
+        let mut cyclic = HashSet::new();
         let mut topo_order = Vec::new();
 
         // Helper function to perform topological sort using DFS
         fn dfs(
             cell: (u16, u16),
             parents_normal: &HashMap<(u16, u16), HashSet<(u16, u16)>>,
-            child_range: &HashMap<(u16, u16), (String, (u16, u16), (u16, u16))>,
+            range_index: &RangeIndex,
             visited: &mut HashSet<(u16, u16)>,
             visiting: &mut HashSet<(u16, u16)>,
+            cyclic: &mut HashSet<(u16, u16)>,
             topo_order: &mut Vec<(u16, u16)>,
         ) {
             if visited.contains(&cell) {
@@ -445,7 +486,8 @@ impl Spreadsheet {
 
             // Check for circular dependencies
             if !visiting.insert(cell) {
-                // We've detected a cycle, just return without adding to topo_order
+                // We've detected a cycle; remember it and don't add it to topo_order again
+                cyclic.insert(cell);
                 return;
             }
 
@@ -455,24 +497,25 @@ impl Spreadsheet {
                     dfs(
                         dependent,
                         parents_normal,
-                        child_range,
+                        range_index,
                         visited,
                         visiting,
+                        cyclic,
                         topo_order,
                     );
                 }
             }
 
             // Check range dependencies
-            for (&range_cell, (_, range_start, range_end)) in child_range {
-                if is_within_range(cell, *range_start, *range_end) && !visited.contains(&range_cell)
-                {
+            for range_cell in range_index.covering(cell) {
+                if !visited.contains(&range_cell) {
                     dfs(
                         range_cell,
                         parents_normal,
-                        child_range,
+                        range_index,
                         visited,
                         visiting,
+                        cyclic,
                         topo_order,
                     );
                 }
@@ -490,116 +533,62 @@ impl Spreadsheet {
                 dfs(
                     cell,
                     &self.parents_normal,
-                    &self.child_range,
+                    &self.range_index,
                     &mut visited,
                     &mut visiting,
+                    &mut cyclic,
                     &mut topo_order,
                 );
             }
         }
 
-        // Process cells in reverse topological order (dependencies before dependents)
-        for cur in topo_order.iter().rev() {
+        // Group the cells into levels in reverse topological order (dependencies
+        // before dependents): a cell's level is one more than that of any cell it
+        // reads, so the cells of one level never read each other.
+        let mut level: HashMap<(u16, u16), usize> = HashMap::new();
+        let mut levels: Vec<Vec<(u16, u16)>> = Vec::new();
+        for &cur in topo_order.iter().rev() {
+            let depth = level.get(&cur).copied().unwrap_or(0);
+            let normal = self.parents_normal.get(&cur).into_iter().flatten().copied();
+            for dependent in normal.chain(self.range_index.covering(cur)) {
+                let entry = level.entry(dependent).or_insert(0);
+                *entry = (*entry).max(depth + 1);
+            }
             // Skip the start cell if it was already updated (e.g., by a set_cell call)
-            if *cur == start {
-                //this change fixed the issue of sleep (earlier it was *cur == start && all_cells_to_update.len() > 1)
+            if Some(cur) == skip {
                 continue;
             }
-
-            // compute new value for `cur`
-            let new_cell = if let Some((formula, _)) = self.child_normal.get(cur).cloned() {
-                // SLEEP function handling
-                if formula.starts_with("SLEEP(") && formula.ends_with(")") {
-                    let arg_str = &formula[6..formula.len() - 1];
-
-                    // Try to parse as a literal integer
-                    if let Ok(sleep_time) = arg_str.parse::<i32>() {
-                        // Direct sleep with constant
-                        if sleep_time > 0 {
-                            thread::sleep(Duration::from_secs(sleep_time as u64));
-                        }
-                        Cell::Value(sleep_time)
-                    }
-                    // Try to parse as a cell reference
-                    else if let Some(ref_cell) = Parser::cell_name_to_coord(arg_str) {
-                        match self.get_val(ref_cell) {
-                            Some(sleep_time) => {
-                                // Sleep using the referenced cell's value
-                                if sleep_time > 0 {
-                                    thread::sleep(Duration::from_secs(sleep_time as u64));
-                                    //this is the issue.
-                                }
-                                Cell::Value(sleep_time)
-                            }
-                            None => Cell::Err,
-                        }
-                    } else {
-                        Cell::Err
-                    }
-                }
-                // binary?
-                else if let Some((op_char, lhs_s, rhs_s)) = Parser::split_binary(&formula) {
-                    let op_code = match op_char {
-                        '+' => 1,
-                        '-' => 2,
-                        '*' => 3,
-                        '/' => 5,
-                        _ => continue, // shouldn't happen
-                    };
-
-                    let a = if let Some(c) = Parser::cell_name_to_coord(lhs_s) {
-                        self.get_val(c)
-                    } else {
-                        lhs_s.parse::<i32>().ok()
-                    };
-                    let b = if let Some(c) = Parser::cell_name_to_coord(rhs_s) {
-                        self.get_val(c)
-                    } else {
-                        rhs_s.parse::<i32>().ok()
-                    };
-
-                    if op_code == 5 && b == Some(0) {
-                        Cell::Err
-                    } else if let (Some(a_val), Some(b_val)) = (a, b) {
-                        if let Some(v) = eval_binary(op_code, a_val, b_val) {
-                            Cell::Value(v)
-                        } else {
-                            Cell::Err
-                        }
-                    } else {
-                        Cell::Err
-                    }
-                }
-                // single‐cell ref?
-                else if let Some(c) = Parser::cell_name_to_coord(&formula) {
-                    match self.get_val(c) {
-                        Some(val) => Cell::Value(val),
-                        None => Cell::Err,
-                    }
-                }
-                // literal?
-                else if let Ok(v) = formula.parse::<i32>() {
-                    Cell::Value(v)
-                } else {
-                    continue;
-                }
+            if levels.len() <= depth {
+                levels.resize(depth + 1, Vec::new());
             }
-            // range?
-            else if let Some((formula, range_start, range_end)) =
-                self.child_range.get(cur).cloned()
-            {
-                let func = &formula[..formula.find('(').unwrap_or(0)];
-                if let Some(v) = eval_range(func, range_start, range_end, |c| self.get_val(c)) {
-                    Cell::Value(v)
+            levels[depth].push(cur);
+        }
+
+        // Evaluate each level, in parallel when it is worth it, before the next
+        let mut overflowed = false;
+        for cells in levels {
+            let compute = |cur: &(u16, u16)| {
+                // compute new value for `cur`
+                let new_cell = if cyclic.contains(cur) {
+                    Cell::Err(CellError::Cycle)
                 } else {
-                    Cell::Err
-                }
+                    let (ast, _) = self.child_normal.get(cur)?;
+                    self.eval_cell(*cur, ast)
+                };
+                Some((*cur, new_cell))
+            };
+            let results: Vec<_> = if self.parallel && cells.len() >= PARALLEL_MIN {
+                cells.par_iter().filter_map(compute).collect()
             } else {
-                continue;
+                cells.iter().filter_map(compute).collect()
             };
-
-            self.cells[cur.1 as usize][cur.0 as usize] = new_cell;
+            for (cur, new_cell) in results {
+                let overflow = Cell::Err(CellError::Overflow);
+                overflowed |= new_cell == overflow && self.cells.get(&cur) != Some(&overflow);
+                self.set_val(cur, new_cell);
+            }
         }
+        overflowed
     }
 
     /// Print a window of the sheet
@@ -613,104 +602,82 @@ impl Spreadsheet {
         for r in (start_row + 1)..=(start_row + max_rows).min(self.rows) {
             print!("{:>3} ", r);
             for c in (start_col + 1)..=(start_col + max_cols).min(self.cols) {
-                match &self.cells[r][c] {
+                match self.cell((c as u16, r as u16)) {
                     Cell::Value(v) => print!("{:>8}", v),
-                    Cell::Err => print!("{:>8}", "ERR"),
+                    Cell::Float(v) => print!("{:>8}", format_float(*v, 7)),
+                    // Text is left-aligned and cut to fit, keeping a space before it
+                    Cell::Text(t) => print!(" {:<7}", t.chars().take(7).collect::<String>()),
+                    Cell::Bool(b) => print!("{:>8}", if *b { "TRUE" } else { "FALSE" }),
+                    // Dates show as ISO text, cut to fit like other text
+                    Cell::Date(d) => print!(
+                        " {:<7}",
+                        format_date(*d).chars().take(7).collect::<String>()
+                    ),
+                    Cell::Err(e) => print!("{:>8}", e.code()),
                 }
             }
             println!();
         }
     }
 
-    // Add this new function to detect cycles in the dependency graph
-    pub fn has_cycle(&self) -> bool {
-        let mut visited = HashSet::new();
-        let mut path = HashSet::new();
-
-        // Create a vector to collect all cells we need to check
-        let mut cells_to_check = Vec::new();
-
-        // Add cells from parents_normal
-        cells_to_check.extend(self.parents_normal.keys().copied());
-
-        // Add cells from child_normal
-        cells_to_check.extend(self.child_normal.keys().copied());
-
-        // Add cells from range dependencies
-        for (&c, (_, start, end)) in &self.child_range {
-            cells_to_check.push(c);
-
-            // Also add all cells within each range
-            for col in start.0..=end.0 {
-                for row in start.1..=end.1 {
-                    cells_to_check.push((col, row));
-                }
-            }
-        }
-
-        // Remove duplicates
-        cells_to_check.sort();
-        cells_to_check.dedup();
-
-        // Check each cell for cycles
-        for cell in cells_to_check {
-            if !visited.contains(&cell) {
-                if self.is_cyclic(cell, &mut visited, &mut path) {
-                    return true;
-                }
-            }
-        }
-
-        false
-    }
-
-    // Helper function for cycle detection using DFS
-    fn is_cyclic(
+    /// Check whether giving `target` a formula that reads `refs` and `ranges`
+    /// would close a loop, before any of its new dependencies are recorded.
+    ///
+    /// The rest of the graph is already free of cycles, so a loop must run from
+    /// `target` through cells that (directly or not) read it and on to one of
+    /// the new precedents.  Only those dependents are searched, using the range
+    /// index for range edges, so the cost depends on how much reads `target`
+    /// rather than on the size of the sheet.  Returns the loop, starting and
+    /// ending at `target`, with each cell followed by one it reads.
+    fn find_cycle(
         &self,
-        cell: (u16, u16),
-        visited: &mut HashSet<(u16, u16)>,
-        path: &mut HashSet<(u16, u16)>,
-    ) -> bool {
-        visited.insert(cell);
-        path.insert(cell);
-
-        // Check normal dependencies
-        if let Some(refs) = self.child_normal.get(&cell) {
-            for &ref_cell in &refs.1 {
-                if !visited.contains(&ref_cell) {
-                    if self.is_cyclic(ref_cell, visited, path) {
-                        return true;
-                    }
-                } else if path.contains(&ref_cell) {
-                    // Found a cycle
-                    return true;
-                }
-            }
+        target: (u16, u16),
+        refs: &HashSet<(u16, u16)>,
+        ranges: &RangeDeps,
+    ) -> Option<Vec<(u16, u16)>> {
+        let reads = |cell: (u16, u16)| refs.contains(&cell) || in_any_range(cell, ranges);
+        if reads(target) {
+            return Some(vec![target, target]);
         }
-
-        // Check range dependencies
-        if let Some((_, start, end)) = self.child_range.get(&cell) {
-            for col in start.0..=end.0 {
-                for row in start.1..=end.1 {
-                    let ref_cell = (col, row);
-                    if !visited.contains(&ref_cell) {
-                        if self.is_cyclic(ref_cell, visited, path) {
-                            return true;
-                        }
-                    } else if path.contains(&ref_cell) {
-                        // Found a cycle
-                        return true;
+        // Depth-first over dependents, remembering how each cell was reached
+        let mut reached_from = HashMap::new();
+        let mut stack = vec![target];
+        while let Some(cell) = stack.pop() {
+            let normal = self
+                .parents_normal
+                .get(&cell)
+                .into_iter()
+                .flatten()
+                .copied();
+            for dependent in normal.chain(self.range_index.covering(cell)) {
+                if dependent == target || reached_from.contains_key(&dependent) {
+                    continue;
+                }
+                reached_from.insert(dependent, cell);
+                if reads(dependent) {
+                    // target reads `dependent`, which reads back along the chain to target
+                    let mut cycle = vec![target, dependent];
+                    let mut cur = dependent;
+                    while let Some(&prev) = reached_from.get(&cur) {
+                        cycle.push(prev);
+                        cur = prev;
                     }
+                    return Some(cycle);
                 }
+                stack.push(dependent);
             }
         }
-
-        // Remove cell from current path as we backtrack
-        path.remove(&cell);
-        false
+        None
     }
 }
 
+// Helper function to check if a cell is within any of a formula's ranges
+fn in_any_range(cell: (u16, u16), ranges: &RangeDeps) -> bool {
+    ranges
+        .iter()
+        .any(|&(start, end)| is_within_range(cell, start, end))
+}
+
 // Helper function to check if a cell is within a range
 fn is_within_range(cell: (u16, u16), start: (u16, u16), end: (u16, u16)) -> bool {
     let (col, row) = cell;
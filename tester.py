@@ -6,16 +6,82 @@ input_files = [
     # "textfiles/test1.txt","textfiles/test2.txt", "textfiles/test3.txt", "textfiles/test4.txt", "textfiles/test5.txt", "textfiles/test6.txt",
     # "textfiles/test7.txt", "textfiles/test8.txt", "textfiles/test9.txt", 
     "textfiles/test10.txt", "textfiles/test11.txt", "textfiles/test12.txt",
+    "textfiles/test13.txt",
+    "textfiles/test14.txt",
+    "textfiles/test15.txt",
+    "textfiles/test16.txt",
+    "textfiles/test17.txt",
+    "textfiles/test18.txt",
+    "textfiles/test19.txt",
+    "textfiles/test20.txt",
+    "textfiles/test21.txt",
+    "textfiles/test22.txt",
+    "textfiles/test23.txt",
+    "textfiles/test24.txt",
+    "textfiles/test25.txt",
+    "textfiles/test26.txt",
+    "textfiles/test27.txt",
+    "textfiles/test28.txt",
+    "textfiles/test29.txt",
+    "textfiles/test30.txt",
+    "textfiles/test31.txt",
+    "textfiles/test32.txt",
+    "textfiles/test33.txt",
+    "textfiles/test34.txt",
 ]
 output_files = [
     # "textfiles/output1.txt","textfiles/output2.txt", "textfiles/output3.txt", "textfiles/output4.txt", "textfiles/output5.txt", "textfiles/output6.txt",
     # "textfiles/output7.txt", "textfiles/output8.txt", "textfiles/output9.txt",
     "textfiles/output10.txt", "textfiles/output11.txt", "textfiles/output12.txt",
+    "textfiles/output13.txt",
+    "textfiles/output14.txt",
+    "textfiles/output15.txt",
+    "textfiles/output16.txt",
+    "textfiles/output17.txt",
+    "textfiles/output18.txt",
+    "textfiles/output19.txt",
+    "textfiles/output20.txt",
+    "textfiles/output21.txt",
+    "textfiles/output22.txt",
+    "textfiles/output23.txt",
+    "textfiles/output24.txt",
+    "textfiles/output25.txt",
+    "textfiles/output26.txt",
+    "textfiles/output27.txt",
+    "textfiles/output28.txt",
+    "textfiles/output29.txt",
+    "textfiles/output30.txt",
+    "textfiles/output31.txt",
+    "textfiles/output32.txt",
+    "textfiles/output33.txt",
+    "textfiles/output34.txt",
 ]
 expected_files = [
     # "textfiles/expected_output1.txt","textfiles/expected_output2.txt", "textfiles/expected_output3.txt", "textfiles/expected_output4.txt", "textfiles/expected_output5.txt", "textfiles/expected_output6.txt",
     # "textfiles/expected_output7.txt", "textfiles/expected_output8.txt", "textfiles/expected_output9.txt",
     "textfiles/expected_output10.txt",  "textfiles/expected_output11.txt", "textfiles/expected_output12.txt",
+    "textfiles/expected_output13.txt",
+    "textfiles/expected_output14.txt",
+    "textfiles/expected_output15.txt",
+    "textfiles/expected_output16.txt",
+    "textfiles/expected_output17.txt",
+    "textfiles/expected_output18.txt",
+    "textfiles/expected_output19.txt",
+    "textfiles/expected_output20.txt",
+    "textfiles/expected_output21.txt",
+    "textfiles/expected_output22.txt",
+    "textfiles/expected_output23.txt",
+    "textfiles/expected_output24.txt",
+    "textfiles/expected_output25.txt",
+    "textfiles/expected_output26.txt",
+    "textfiles/expected_output27.txt",
+    "textfiles/expected_output28.txt",
+    "textfiles/expected_output29.txt",
+    "textfiles/expected_output30.txt",
+    "textfiles/expected_output31.txt",
+    "textfiles/expected_output32.txt",
+    "textfiles/expected_output33.txt",
+    "textfiles/expected_output34.txt",
 ]
 rows = 999
 cols = 18278